@@ -24,8 +24,10 @@ fn main() {
     opts.set_reconnect(ReconnectMethod::ReconnectAfter(Duration::new(5,0)));
     let mut client = opts.connect(address).unwrap();
 
-    client.subscribe(topic.as_str()).unwrap();
-    client.await().unwrap();
+    let token = client.subscribe(topic.as_str()).unwrap();
+    for result in client.wait(&token, Some(Duration::new(5, 0))).unwrap() {
+        println!("Subscribed to {} with {:?}", result.topic, result.granted);
+    }
     //client.subscribe("/a/b/c").unwrap();
     //client.subscribe("a/b/c").unwrap();
     //client.publish(topic.as_str(), "Hello", PubOpt::at_least_once());
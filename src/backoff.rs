@@ -0,0 +1,112 @@
+use std::time::Duration;
+use rand::Rng;
+
+/// How a backoff delay is randomized, so that many clients disconnected at
+/// the same time don't reconnect in lockstep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Jitter {
+    /// Exactly `initial * 2^attempt`, capped
+    None,
+    /// Uniformly random between zero and the capped exponential delay
+    Full,
+    /// Uniformly random between `initial` and three times the previous delay
+    Decorrelated,
+}
+
+/// Exponential backoff between reconnect attempts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub jitter: Jitter,
+    /// Give up after this many failed attempts, `None` retries forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial: initial,
+            max: max,
+            jitter: Jitter::None,
+            max_attempts: None,
+        }
+    }
+
+    pub fn with_jitter(mut self, jitter: Jitter) -> Backoff {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Backoff {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// Delay before the `attempt`-th reconnect (counting from zero), given
+    /// the delay used for the previous one.
+    pub fn delay<R: Rng>(&self, attempt: u32, previous: Duration, rng: &mut R) -> Duration {
+        let initial = as_millis(self.initial);
+        let max = as_millis(self.max);
+        let exponential = 1u64.checked_shl(attempt)
+            .and_then(|factor| initial.checked_mul(factor))
+            .map_or(max, |delay| if delay < max { delay } else { max });
+
+        let delay = match self.jitter {
+            Jitter::None => exponential,
+            Jitter::Full => random_between(0, exponential, rng),
+            Jitter::Decorrelated => {
+                let upper = as_millis(previous).saturating_mul(3);
+                let upper = if upper > initial { upper } else { initial };
+                let delay = random_between(initial, upper, rng);
+                if delay < max { delay } else { max }
+            }
+        };
+        Duration::from_millis(delay)
+    }
+}
+
+fn as_millis(dur: Duration) -> u64 {
+    dur.as_secs().saturating_mul(1000) + (dur.subsec_nanos() / 1_000_000) as u64
+}
+
+fn random_between<R: Rng>(low: u64, high: u64, rng: &mut R) -> u64 {
+    let unit: f64 = rng.gen();
+    low + ((high - low) as f64 * unit) as u64
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use rand;
+    use super::{Backoff, Jitter};
+
+    #[test]
+    fn backoff_exponential_test() {
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+        let mut rng = rand::thread_rng();
+        let zero = Duration::new(0, 0);
+        assert_eq!(backoff.delay(0, zero, &mut rng), Duration::from_millis(100));
+        assert_eq!(backoff.delay(1, zero, &mut rng), Duration::from_millis(200));
+        assert_eq!(backoff.delay(3, zero, &mut rng), Duration::from_millis(800));
+        assert_eq!(backoff.delay(4, zero, &mut rng), Duration::from_secs(1));
+        assert_eq!(backoff.delay(200, zero, &mut rng), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn backoff_jitter_test() {
+        let mut rng = rand::thread_rng();
+        let full = Backoff::new(Duration::from_millis(100), Duration::from_secs(1))
+            .with_jitter(Jitter::Full);
+        let decorrelated = full.with_jitter(Jitter::Decorrelated);
+        let mut previous = Duration::from_millis(100);
+        for attempt in 0..20 {
+            assert!(full.delay(attempt, previous, &mut rng) <= Duration::from_secs(1));
+
+            let delay = decorrelated.delay(attempt, previous, &mut rng);
+            assert!(delay >= Duration::from_millis(100));
+            assert!(delay <= Duration::from_secs(1));
+            previous = delay;
+        }
+    }
+}
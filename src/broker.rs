@@ -0,0 +1,424 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::time::Duration;
+use std::{cmp, thread};
+use url::Url;
+use mqtt3::{self, MqttRead, MqttWrite, Message, Packet, PacketIdentifier, QoS, Protocol, LastWill,
+            ConnectReturnCode, SubscribeReturnCodes, ToTopicPath};
+use netopt::{NetworkListener, NetworkStream, TcpListener};
+use error::Result;
+use router::topic_matches;
+
+enum Command {
+    Send(Packet),
+    Close,
+}
+
+struct Session {
+    connection: usize,
+    tx: Sender<Command>,
+    subscriptions: Vec<(String, QoS)>,
+    last_pid: u16,
+}
+
+#[derive(Default)]
+struct State {
+    sessions: HashMap<String, Session>,
+    retained: BTreeMap<String, Message>,
+}
+
+impl State {
+    /// Sends `message` to every session subscribed to its topic, once per
+    /// session at the highest granted QoS.
+    fn route(&mut self, message: &Message) {
+        let topic = message.topic.path();
+        for session in self.sessions.values_mut() {
+            let granted = session.subscriptions
+                .iter()
+                .filter(|&&(ref filter, _)| topic_matches(filter, &topic))
+                .map(|&(_, qos)| qos.to_u8())
+                .max();
+            if let Some(granted) = granted {
+                let qos = min_qos(message.qos, granted);
+                session.deliver(message, qos, false);
+            }
+        }
+    }
+
+    fn retain(&mut self, message: &Message) {
+        let topic = message.topic.path();
+        if message.payload.is_empty() {
+            self.retained.remove(&topic);
+        } else {
+            self.retained.insert(topic, message.clone());
+        }
+    }
+}
+
+impl Session {
+    fn deliver(&mut self, message: &Message, qos: QoS, retain: bool) {
+        let mut message = message.clone();
+        message.qos = qos;
+        message.retain = retain;
+        message.pid = match qos {
+            QoS::AtMostOnce => None,
+            _ => {
+                self.last_pid = self.last_pid.wrapping_add(1);
+                if self.last_pid == 0 {
+                    self.last_pid = 1;
+                }
+                Some(PacketIdentifier(self.last_pid))
+            }
+        };
+        let _ = self.tx.send(Command::Send(Packet::Publish(message.to_pub(None, false))));
+    }
+}
+
+fn min_qos(qos: QoS, max: u8) -> QoS {
+    QoS::from_u8(cmp::min(qos.to_u8(), max)).unwrap()
+}
+
+/// A minimal in-process broker for tests.
+///
+/// Supports QoS 0 to 2, retained messages, wills and wildcard
+/// subscriptions. Sessions are always clean and unacknowledged deliveries
+/// are never retransmitted. Each connection is served by its own threads.
+pub struct Broker {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Broker {
+    /// Starts a broker on an ephemeral port of the loopback interface.
+    pub fn start() -> Result<Broker> {
+        let listener = try!(TcpListener::bind("127.0.0.1:0"));
+        Broker::with_listener(listener)
+    }
+
+    /// Serves the connections accepted by `listener`. Only TCP listeners
+    /// are accepted, since dropping the broker wakes the accepting thread
+    /// up by connecting to it.
+    pub fn with_listener(mut listener: TcpListener) -> Result<Broker> {
+        let addr = try!(listener.local_addr());
+        let state = Arc::new(Mutex::new(State::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let (accept_state, accept_stop) = (state.clone(), stop.clone());
+        thread::spawn(move || {
+            for accepted in listener.incoming() {
+                if accept_stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                match accepted {
+                    Ok((stream, peer)) => {
+                        let connection = connections.fetch_add(1, Ordering::SeqCst) + 1;
+                        let state = accept_state.clone();
+                        debug!("Broker accepted {} from {}", connection, peer);
+                        thread::spawn(move || serve(stream, connection, state));
+                    }
+                    Err(err) => warn!("Broker failed to accept: {:?}", err),
+                }
+            }
+        });
+
+        Ok(Broker {
+            addr: addr,
+            state: state,
+            stop: stop,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// `mqtt://` url of the broker.
+    pub fn url(&self) -> Url {
+        Url::parse(&format!("mqtt://{}", self.addr)).unwrap()
+    }
+
+    /// Number of connected clients.
+    pub fn clients(&self) -> usize {
+        self.state.lock().unwrap().sessions.len()
+    }
+}
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        for session in self.state.lock().unwrap().sessions.values() {
+            let _ = session.tx.send(Command::Close);
+        }
+        // wake up the accepting thread
+        let mut addr = self.addr;
+        if addr.ip().is_unspecified() {
+            let loopback: IpAddr = match addr {
+                SocketAddr::V4(_) => Ipv4Addr::new(127, 0, 0, 1).into(),
+                SocketAddr::V6(_) => Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1).into(),
+            };
+            addr.set_ip(loopback);
+        }
+        let _ = net::TcpStream::connect(addr);
+    }
+}
+
+fn serve<S>(mut stream: S, connection: usize, state: Arc<Mutex<State>>)
+    where S: NetworkStream + Clone + 'static
+{
+    let (tx, rx) = channel();
+    let mut writer = stream.clone();
+    thread::spawn(move || {
+        for command in rx {
+            match command {
+                Command::Send(packet) => {
+                    if writer.write_packet(&packet).is_err() || writer.flush().is_err() {
+                        break;
+                    }
+                }
+                Command::Close => break,
+            }
+        }
+        let _ = writer.shutdown(Shutdown::Both);
+    });
+
+    let connect = match stream.read_packet() {
+        Ok(Packet::Connect(connect)) => connect,
+        _ => {
+            let _ = tx.send(Command::Close);
+            return;
+        }
+    };
+    match connect.protocol {
+        Protocol::MQTT(4) | Protocol::MQIsdp(3) => (),
+        _ => {
+            let connack = mqtt3::Connack {
+                session_present: false,
+                code: ConnectReturnCode::RefusedProtocolVersion,
+            };
+            let _ = tx.send(Command::Send(Packet::Connack(connack)));
+            let _ = tx.send(Command::Close);
+            return;
+        }
+    }
+    if connect.keep_alive > 0 {
+        // the client is gone after one and a half keep alive periods
+        let timeout = Duration::from_millis(connect.keep_alive as u64 * 1500);
+        let _ = stream.set_read_timeout(Some(timeout));
+    }
+
+    let client_id = if connect.client_id.is_empty() {
+        format!("broker_{}", connection)
+    } else {
+        connect.client_id.clone()
+    };
+    {
+        let mut state = state.lock().unwrap();
+        let session = Session {
+            connection: connection,
+            tx: tx.clone(),
+            subscriptions: Vec::new(),
+            last_pid: 0,
+        };
+        // a client connecting again takes over the session
+        if let Some(old) = state.sessions.insert(client_id.clone(), session) {
+            let _ = old.tx.send(Command::Close);
+        }
+        let connack = mqtt3::Connack { session_present: false, code: ConnectReturnCode::Accepted };
+        let _ = tx.send(Command::Send(Packet::Connack(connack)));
+    }
+
+    let clean = handle_packets(&mut stream, &client_id, &tx, &state);
+
+    let mut state = state.lock().unwrap();
+    let current = state.sessions.get(&client_id).map_or(false, |s| s.connection == connection);
+    if current {
+        state.sessions.remove(&client_id);
+    }
+    if !clean {
+        if let Some(ref will) = connect.last_will {
+            debug!("Broker publishes the will of {}", client_id);
+            if let Some(message) = will_message(will) {
+                if message.retain {
+                    state.retain(&message);
+                }
+                state.route(&message);
+            }
+        }
+    }
+    let _ = tx.send(Command::Close);
+}
+
+/// Handles the packets of a connected client. Returns whether it sent
+/// DISCONNECT.
+fn handle_packets<S>(stream: &mut S,
+                     client_id: &str,
+                     tx: &Sender<Command>,
+                     state: &Arc<Mutex<State>>)
+                     -> bool
+    where S: NetworkStream
+{
+    // QoS 2 publishes waiting for PUBREL
+    let mut unreleased = BTreeMap::new();
+    loop {
+        let packet = match stream.read_packet() {
+            Ok(packet) => packet,
+            Err(err) => {
+                debug!("Broker lost {}: {:?}", client_id, err);
+                return false;
+            }
+        };
+        let reply = match packet {
+            Packet::Publish(publish) => {
+                let message = match Message::from_pub(publish) {
+                    Ok(message) => message,
+                    Err(_) => return false,
+                };
+                match message.qos {
+                    QoS::AtMostOnce => {
+                        publish_message(state, &message);
+                        None
+                    }
+                    QoS::AtLeastOnce => {
+                        publish_message(state, &message);
+                        message.pid.map(Packet::Puback)
+                    }
+                    QoS::ExactlyOnce => {
+                        let pid = message.pid;
+                        if let Some(pid) = pid {
+                            unreleased.insert(pid, message);
+                        }
+                        pid.map(Packet::Pubrec)
+                    }
+                }
+            }
+            Packet::Pubrel(pid) => {
+                if let Some(message) = unreleased.remove(&pid) {
+                    publish_message(state, &message);
+                }
+                Some(Packet::Pubcomp(pid))
+            }
+            Packet::Pubrec(pid) => Some(Packet::Pubrel(pid)),
+            Packet::Puback(_) | Packet::Pubcomp(_) => None,
+            Packet::Subscribe(subscribe) => {
+                let mut state = state.lock().unwrap();
+                let retained: Vec<Message> = state.retained.values().cloned().collect();
+                let session = match state.sessions.get_mut(client_id) {
+                    Some(session) => session,
+                    None => return false,
+                };
+                let mut return_codes = Vec::with_capacity(subscribe.topics.len());
+                for topic in &subscribe.topics {
+                    session.subscriptions.retain(|&(ref filter, _)| *filter != topic.topic_path);
+                    session.subscriptions.push((topic.topic_path.clone(), topic.qos));
+                    return_codes.push(SubscribeReturnCodes::Success(topic.qos));
+                }
+                let suback = mqtt3::Suback { pid: subscribe.pid, return_codes: return_codes };
+                let _ = tx.send(Command::Send(Packet::Suback(suback)));
+                for topic in &subscribe.topics {
+                    for message in &retained {
+                        if topic_matches(&topic.topic_path, &message.topic.path()) {
+                            let qos = min_qos(message.qos, topic.qos.to_u8());
+                            session.deliver(message, qos, true);
+                        }
+                    }
+                }
+                None
+            }
+            Packet::Unsubscribe(unsubscribe) => {
+                let mut state = state.lock().unwrap();
+                if let Some(session) = state.sessions.get_mut(client_id) {
+                    session.subscriptions
+                        .retain(|&(ref filter, _)| !unsubscribe.topics.contains(filter));
+                }
+                Some(Packet::Unsuback(unsubscribe.pid))
+            }
+            Packet::Pingreq => Some(Packet::Pingresp),
+            Packet::Disconnect => return true,
+            packet => {
+                warn!("Broker got unexpected {:?} from {}", packet, client_id);
+                return false;
+            }
+        };
+        if let Some(reply) = reply {
+            let _ = tx.send(Command::Send(reply));
+        }
+    }
+}
+
+fn publish_message(state: &Arc<Mutex<State>>, message: &Message) {
+    let mut state = state.lock().unwrap();
+    if message.retain {
+        state.retain(message);
+    }
+    state.route(message);
+}
+
+fn will_message(will: &LastWill) -> Option<Message> {
+    let topic = match will.topic.to_topic_name() {
+        Ok(topic) => topic,
+        Err(_) => return None,
+    };
+    Some(Message {
+        topic: topic,
+        qos: will.qos,
+        retain: will.retain,
+        pid: None,
+        payload: Arc::new(will.message.clone().into_bytes()),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::Broker;
+    use client::ClientOptions;
+    use mqtt3::QoS;
+    use {PubSub, PubOpt};
+
+    #[test]
+    fn broker_test() {
+        let broker = Broker::start().unwrap();
+
+        let mut sub = ClientOptions::new().connect(&broker.url()).unwrap();
+        let token = sub.subscribe(("a/+", QoS::ExactlyOnce)).unwrap();
+        sub.wait(&token, Some(Duration::from_secs(5))).unwrap();
+
+        let mut publisher = ClientOptions::new().connect(&broker.url()).unwrap();
+        publisher.publish_confirmed("a/b", "retained", PubOpt::at_least_once() | PubOpt::retain(),
+                               Duration::from_secs(5))
+            .unwrap();
+        publisher.publish_confirmed("a/c", "exactly once", PubOpt::exactly_once(),
+                               Duration::from_secs(5))
+            .unwrap();
+        publisher.publish("b", "not routed", PubOpt::at_most_once()).unwrap();
+
+        let message = sub.await().unwrap().unwrap();
+        assert_eq!(message.topic.path(), "a/b");
+        assert_eq!(message.qos, QoS::AtLeastOnce);
+        assert!(!message.retain);
+        let message = sub.await().unwrap().unwrap();
+        assert_eq!(&message.payload[..], b"exactly once");
+        assert_eq!(message.qos, QoS::ExactlyOnce);
+        sub.complete(message.pid.unwrap()).unwrap();
+
+        // late subscribers get the retained message and the will
+        let mut late = ClientOptions::new().connect(&broker.url()).unwrap();
+        late.subscribe(("#", QoS::AtMostOnce)).unwrap();
+        let message = late.await().unwrap().unwrap();
+        assert_eq!(message.topic.path(), "a/b");
+        assert!(message.retain);
+
+        let mut options = ClientOptions::new();
+        options.set_last_will("status/will", "gone".to_string(), PubOpt::at_most_once()).unwrap();
+        let mut will = options.connect(&broker.url()).unwrap();
+        will.terminate();
+        let message = late.await().unwrap().unwrap();
+        assert_eq!(message.topic.path(), "status/will");
+        assert_eq!(&message.payload[..], b"gone");
+    }
+}
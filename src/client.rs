@@ -1,34 +1,116 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::io::{Write, ErrorKind};
-use std::net::{ToSocketAddrs, Shutdown};
+use std::net::Shutdown;
 use std::time::{Duration, Instant};
-use std::{thread, result};
-use netopt::{HostAndPort, NetworkConnector, NetworkStream, TcpConnector, SslConnector, BoxedConnector};
+use std::{cmp, thread, result};
+use netopt::{HostAndPort, NetworkConnector, NetworkStream, TcpConnector, WsConnector, BoxedConnector,
+             Proxy, TlsOptions};
+#[cfg(feature = "ssl")]
+use netopt::SslConnector;
+#[cfg(all(feature = "rustls", not(feature = "ssl")))]
+use netopt::RustlsConnector;
+#[cfg(unix)]
+use netopt::UnixConnector;
+#[cfg(unix)]
+use url::Host;
 use url::Url;
 use rand::{self, Rng};
 use mqtt3::{MqttRead, MqttWrite, Message, QoS, SubscribeReturnCodes, SubscribeTopic};
 use mqtt3::{self, Protocol, Packet, ConnectReturnCode, PacketIdentifier, LastWill, ToTopicPath};
 use store::MemoryStorage;
 use error::{Error, Result};
-use sub::Subscription;
-use {PubSub, ClientState, ReconnectMethod, PubOpt, ToPayload, ToSubTopics, ToUnSubTopics};
+use sub::{Subscription, SubscribeResult};
+use token::{token, Token, Completion};
+use endpoints::{Endpoints, parse_urls};
+use router::{Router, RouteId};
+use {PubSub, ClientState, ReconnectMethod, FailoverPolicy, OverflowPolicy, PubOpt, ToPayload,
+     ToSubTopics, ToUnSubTopics, Undelivered, SubscribeToken, DeliveryToken};
 use store::Store;
+use inflight::{InFlight, Outgoing, Incomming};
+use metrics::{self, Metrics, CountingReader};
+use interceptor::{intercept, Interceptor, Verdict};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transport {
+    Tcp,
+    Ssl,
+    Ws,
+    Wss,
+    #[cfg(unix)]
+    Unix,
+    #[cfg(unix)]
+    UnixSsl,
+}
 
-fn is_ssl(url: &Url) -> result::Result<bool, ()> {
+fn transport(url: &Url) -> result::Result<Transport, ()> {
     match url.scheme() {
-        "tcp" | "mqtt" => Ok(false),
-        "tls" | "ssl" | "mqtts" => Ok(true),
+        "tcp" | "mqtt" => Ok(Transport::Tcp),
+        "tls" | "ssl" | "mqtts" => Ok(Transport::Ssl),
+        "ws" => Ok(Transport::Ws),
+        "wss" => Ok(Transport::Wss),
+        #[cfg(unix)]
+        "unix" => Ok(Transport::Unix),
+        #[cfg(unix)]
+        "unix+tls" | "unix+ssl" => Ok(Transport::UnixSsl),
         _ => Err(()),
     }
 }
 
 fn default_port(url: &Url) -> result::Result<u16, ()> {
-    is_ssl(url).map(|is_ssl| if is_ssl { 8883 } else { 1883 })
+    transport(url).map(|transport| match transport {
+        Transport::Tcp => 1883,
+        Transport::Ssl => 8883,
+        Transport::Ws => 80,
+        Transport::Wss => 443,
+        #[cfg(unix)]
+        Transport::Unix | Transport::UnixSsl => 0,
+    })
+}
+
+#[cfg(feature = "ssl")]
+fn tls_connector<C>(connector: C, options: &TlsOptions) -> Result<BoxedConnector>
+    where C: NetworkConnector + 'static
+{
+    Ok(BoxedConnector::new(try!(SslConnector::with_options(connector, options))))
+}
+
+#[cfg(all(feature = "rustls", not(feature = "ssl")))]
+fn tls_connector<C>(connector: C, options: &TlsOptions) -> Result<BoxedConnector>
+    where C: NetworkConnector + 'static
+{
+    Ok(BoxedConnector::new(try!(RustlsConnector::with_options(connector, options))))
+}
+
+#[cfg(not(any(feature = "ssl", feature = "rustls")))]
+fn tls_connector<C>(_connector: C, _options: &TlsOptions) -> Result<BoxedConnector>
+    where C: NetworkConnector + 'static
+{
+    Err(Error::TlsUnavailable)
+}
+
+/// MQTT 3.1 limits client identifiers to this many characters.
+const MQISDP_MAX_CLIENT_ID: usize = 23;
+
+fn protocol_level(protocol: Protocol) -> u8 {
+    match protocol {
+        Protocol::MQIsdp(level) | Protocol::MQTT(level) => level,
+    }
+}
+
+fn to_message<T: ToTopicPath, P: ToPayload>(topic: T, payload: P, pubopt: PubOpt) -> Result<Message> {
+    Ok(Message {
+        topic: try!(topic.to_topic_name()),
+        qos: pubopt.qos(),
+        retain: pubopt.is_retain(),
+        pid: None,
+        payload: payload.to_payload(),
+    })
 }
 
 // #[derive(Clone)]
 pub struct ClientOptions {
     protocol: Protocol,
+    protocol_fallback: bool,
     keep_alive: Option<Duration>,
     clean_session: bool,
     client_id: Option<String>,
@@ -36,6 +118,13 @@ pub struct ClientOptions {
     username: Option<String>,
     password: Option<String>,
     reconnect: ReconnectMethod,
+    failover: FailoverPolicy,
+    max_inflight: Option<usize>,
+    offline_queue: Option<(usize, OverflowPolicy)>,
+    ws_headers: Vec<(String, String)>,
+    proxy: Option<Proxy>,
+    tls: TlsOptions,
+    interceptors: Vec<Box<Interceptor>>,
 
     incomming_store: Option<Box<Store + Send>>,
     outgoing_store: Option<Box<Store + Send>>,
@@ -45,6 +134,7 @@ impl ClientOptions {
     pub fn new() -> ClientOptions {
         ClientOptions {
             protocol: Protocol::MQTT(4),
+            protocol_fallback: false,
             keep_alive: Some(Duration::new(30, 0)),
             clean_session: true,
             client_id: None,
@@ -52,6 +142,13 @@ impl ClientOptions {
             username: None,
             password: None,
             reconnect: ReconnectMethod::ForeverDisconnect,
+            failover: FailoverPolicy::PriorityFirst,
+            max_inflight: None,
+            offline_queue: None,
+            ws_headers: Vec::new(),
+            proxy: None,
+            tls: TlsOptions::new(),
+            interceptors: Vec::new(),
             incomming_store: Some(MemoryStorage::new()),
             outgoing_store: Some(MemoryStorage::new()),
         }
@@ -67,6 +164,13 @@ impl ClientOptions {
         self
     }
 
+    /// Retries the handshake with MQTT 3.1 (MQIsdp) when the broker refuses
+    /// MQTT 3.1.1 with an unacceptable protocol version.
+    pub fn set_protocol_fallback(&mut self, fallback: bool) -> &mut ClientOptions {
+        self.protocol_fallback = fallback;
+        self
+    }
+
     pub fn set_client_id(&mut self, client_id: String) -> &mut ClientOptions {
         self.client_id = Some(client_id);
         self
@@ -87,6 +191,14 @@ impl ClientOptions {
         self
     }
 
+    /// Adds an interceptor, run on every packet after those added before.
+    pub fn add_interceptor<I>(&mut self, interceptor: I) -> &mut ClientOptions
+        where I: Interceptor + 'static
+    {
+        self.interceptors.push(Box::new(interceptor));
+        self
+    }
+
     pub fn generate_client_id(&mut self) -> &mut ClientOptions {
         let mut rng = rand::thread_rng();
         let id = rng.gen::<u32>();
@@ -129,33 +241,173 @@ impl ClientOptions {
         self
     }
 
+    /// Limits the number of QoS 1 and QoS 2 publishes waiting for an
+    /// acknowledgement. `publish` blocks while the window is full. Zero
+    /// means unlimited, which is the default.
+    pub fn set_max_inflight(&mut self, max_inflight: usize) -> &mut ClientOptions {
+        self.max_inflight = if max_inflight == 0 { None } else { Some(max_inflight) };
+        self
+    }
+
+    /// Keeps up to `capacity` messages published while disconnected and
+    /// publishes them once the client has reconnected.
+    pub fn set_offline_queue(&mut self,
+                             capacity: usize,
+                             overflow: OverflowPolicy)
+                             -> &mut ClientOptions {
+        self.offline_queue = Some((capacity, overflow));
+        self
+    }
+
+    /// How reconnects pick a broker when several were given to `connect_any`.
+    pub fn set_failover_policy(&mut self, policy: FailoverPolicy) -> &mut ClientOptions {
+        self.failover = policy;
+        self
+    }
+
+    /// Adds a header to the WebSocket upgrade request of `ws://` and `wss://`
+    /// connections.
+    pub fn add_ws_header(&mut self, name: String, value: String) -> &mut ClientOptions {
+        self.ws_headers.push((name, value));
+        self
+    }
+
+    /// Reaches the broker through an HTTP CONNECT or SOCKS5 proxy, see
+    /// `Proxy::from_url`. Unix socket urls don't use it.
+    pub fn set_proxy(&mut self, proxy: Proxy) -> &mut ClientOptions {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// CA certificates, client certificate and other settings for TLS
+    /// connections.
+    pub fn set_tls_options(&mut self, tls: TlsOptions) -> &mut ClientOptions {
+        self.tls = tls;
+        self
+    }
+
     pub fn connect(self, url: &Url) -> Result<Client<BoxedConnector>> {
-        let is_ssl = try!(is_ssl(url).map_err(|_| Error::InvalidUrlScheme(url.clone())));
-        let host_port = try!(url.with_default_port(default_port)).to_owned();
-        let connector = TcpConnector::new();
-        let connector = if is_ssl {
-            BoxedConnector::new(try!(SslConnector::new(connector)))
+        self.connect_any(&[url.clone()])
+    }
+
+    /// Connects to one of the brokers listed in a `mqtt://a:1883,b:1883`
+    /// style string.
+    pub fn connect_str(self, urls: &str) -> Result<Client<BoxedConnector>> {
+        let urls = try!(parse_urls(urls));
+        self.connect_any(&urls)
+    }
+
+    /// Connects to the first reachable broker. All urls must share the same
+    /// transport. WebSocket connections request the path of the first url,
+    /// or `/mqtt` if it has none.
+    ///
+    /// `unix:///run/mqtt.sock` connects to a unix socket, and
+    /// `unix+tls://broker.local/run/mqtt.sock` runs TLS over it, verifying
+    /// the certificate against the host (`localhost` when omitted). Only a
+    /// single unix socket url is accepted.
+    pub fn connect_any(self, urls: &[Url]) -> Result<Client<BoxedConnector>> {
+        let (kind, path) = match urls.first() {
+            Some(url) => {
+                let kind = try!(transport(url).map_err(|_| Error::InvalidUrlScheme(url.clone())));
+                let path = match url.query() {
+                    Some(query) => format!("{}?{}", url.path(), query),
+                    None => url.path().to_string(),
+                };
+                (kind, path)
+            }
+            None => return Err(Error::NoEndpoint),
+        };
+        #[cfg(unix)]
+        {
+            if kind == Transport::Unix || kind == Transport::UnixSsl {
+                return self._connect_unix(kind, urls);
+            }
+        }
+        let mut hosts = Vec::with_capacity(urls.len());
+        for url in urls {
+            if transport(url) != Ok(kind) {
+                return Err(Error::InvalidUrlScheme(url.clone()));
+            }
+            hosts.push(try!(url.with_default_port(default_port)).to_owned());
+        }
+        let connector = match self.proxy {
+            Some(ref proxy) => proxy.connector(TcpConnector::new()),
+            None => BoxedConnector::new(TcpConnector::new()),
+        };
+        let connector = match kind {
+            Transport::Tcp => connector,
+            Transport::Ssl => try!(tls_connector(connector, &self.tls)),
+            Transport::Ws => BoxedConnector::new(self._ws_connector(connector, path)),
+            Transport::Wss => {
+                let connector = try!(tls_connector(connector, &self.tls));
+                BoxedConnector::new(self._ws_connector(connector, path))
+            }
+            #[cfg(unix)]
+            Transport::Unix | Transport::UnixSsl => unreachable!(),
+        };
+        self.connect_with_endpoints(connector, hosts)
+    }
+
+    #[cfg(unix)]
+    fn _connect_unix(self, kind: Transport, urls: &[Url]) -> Result<Client<BoxedConnector>> {
+        if urls.len() > 1 {
+            return Err(Error::InvalidUrlScheme(urls[1].clone()));
+        }
+        let url = &urls[0];
+        let host = match url.host_str() {
+            Some(host) if !host.is_empty() => host.to_string(),
+            _ => "localhost".to_string(),
+        };
+        let host = HostAndPort { host: Host::Domain(host), port: 0 };
+        let connector = UnixConnector::new(url.path());
+        let connector = if kind == Transport::UnixSsl {
+            try!(tls_connector(connector, &self.tls))
         } else {
             BoxedConnector::new(connector)
         };
-        self.connect_with(connector, &host_port)
+        self.connect_with_endpoints(connector, vec![host])
+    }
+
+    fn _ws_connector<C: NetworkConnector>(&self, connector: C, path: String) -> WsConnector<C> {
+        let mut connector = WsConnector::new(connector);
+        if path != "/" {
+            connector = connector.with_path(path);
+        }
+        for &(ref name, ref value) in &self.ws_headers {
+            connector = connector.with_header(name.clone(), value.clone());
+        }
+        connector
     }
 
-    pub fn connect_with<C: NetworkConnector + 'static>(mut self,
+    pub fn connect_with<C: NetworkConnector + 'static>(self,
                                                        connector: C,
                                                        host_port: &HostAndPort)
                                                        -> Result<Client<C>> {
+        self.connect_with_endpoints(connector, vec![host_port.clone()])
+    }
+
+    pub fn connect_with_endpoints<C>(mut self,
+                                     connector: C,
+                                     hosts: Vec<HostAndPort>)
+                                     -> Result<Client<C>>
+        where C: NetworkConnector + 'static
+    {
+        if hosts.is_empty() {
+            return Err(Error::NoEndpoint);
+        }
         if self.client_id == None {
             self.generate_client_id();
         }
+        try!(self._check_client_id(self.protocol));
 
-        let addr = try!(host_port.to_socket_addrs()).next().unwrap();
-        info!(" Connecting to {}", addr);
-        let stream = try!(self._reconnect(&connector, host_port));
+        let mut endpoints = Endpoints::new(hosts, self.failover);
+        let order = endpoints.connect_order();
+        let (active, stream) = try!(self._reconnect_any(&connector, &endpoints, &order));
+        endpoints.set_active(active);
 
         let mut client = Client {
             connector: connector,
-            host_port: host_port.clone(),
+            endpoints: endpoints,
             state: ClientState::Disconnected,
             opts: self,
             stream: stream,
@@ -165,19 +417,39 @@ impl ClientOptions {
             last_flush: Instant::now(),
             last_pid: PacketIdentifier::zero(),
             await_ping: false,
-            incomming_pub: VecDeque::new(),
-            incomming_rec: VecDeque::new(),
-            incomming_rel: VecDeque::new(),
-            outgoing_ack: VecDeque::new(),
-            outgoing_rec: VecDeque::new(),
-            outgoing_comp: VecDeque::new(),
-            await_suback: VecDeque::new(),
-            await_unsuback: VecDeque::new(),
+            ping_sent: None,
+            incomming: InFlight::new(),
+            outgoing: InFlight::new(),
+            await_suback: InFlight::new(),
+            await_unsuback: InFlight::new(),
+            delivered: VecDeque::new(),
+            offline: VecDeque::new(),
+            delivery: BTreeMap::new(),
+            router: Router::new(),
             subscriptions: HashMap::new(), // Subscriptions
+            metrics: Metrics::default(),
         };
 
-        // Send CONNECT then wait CONNACK
-        try!(client._handshake());
+        // Pick up flows a previous process left in the stores
+        client._load_session();
+
+        // Send CONNECT then wait CONNACK, failing over to the remaining
+        // endpoints if the broker doesn't accept the connection
+        if let Err(err) = client._handshake() {
+            warn!("Handshake with {} failed: {:?}", client.endpoints.active(), err);
+            client._unbind();
+            let rest: Vec<usize> = order.iter()
+                .cloned()
+                .skip_while(|&idx| idx != active)
+                .skip(1)
+                .collect();
+            if rest.is_empty() {
+                return Err(err);
+            }
+            try!(client._connect_any(&rest));
+        }
+        try!(client._resume_session());
+        try!(client._flush());
 
         Ok(client)
     }
@@ -191,6 +463,42 @@ impl ClientOptions {
         Ok(stream)
     }
 
+    /// Opens a stream to the first endpoint in `order` that accepts it.
+    fn _reconnect_any<C>(&self,
+                         connector: &C,
+                         endpoints: &Endpoints,
+                         order: &[usize])
+                         -> Result<(usize, C::Stream)>
+        where C: NetworkConnector
+    {
+        let mut last_err = Error::NoEndpoint;
+        for &idx in order {
+            let host_port = endpoints.get(idx);
+            info!(" Connecting to {}", host_port);
+            match self._reconnect(connector, host_port) {
+                Ok(stream) => return Ok((idx, stream)),
+                Err(err) => {
+                    warn!("Unable to connect to {}: {:?}", host_port, err);
+                    last_err = err;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Checks the client identifier against the constraints of `protocol`.
+    fn _check_client_id(&self, protocol: Protocol) -> Result<()> {
+        let client_id = self.client_id.as_ref().unwrap();
+        match protocol_level(protocol) {
+            3 if client_id.is_empty() || client_id.chars().count() > MQISDP_MAX_CLIENT_ID => {
+                Err(Error::InvalidClientId)
+            }
+            // only 3.1.1 lets the broker assign an identifier to clean sessions
+            _ if client_id.is_empty() && !self.clean_session => Err(Error::InvalidClientId),
+            _ => Ok(()),
+        }
+    }
+
     fn _generate_connect_packet(&self) -> mqtt3::Connect {
         let keep_alive = if let Some(dur) = self.keep_alive {
             dur.as_secs() as u16
@@ -213,7 +521,7 @@ impl ClientOptions {
 pub struct Client<C: NetworkConnector = BoxedConnector> {
     connector: C,
     stream: C::Stream,
-    host_port: HostAndPort,
+    endpoints: Endpoints,
     state: ClientState,
     opts: ClientOptions,
     session_present: bool,
@@ -222,16 +530,23 @@ pub struct Client<C: NetworkConnector = BoxedConnector> {
     last_flush: Instant,
     last_pid: PacketIdentifier,
     await_ping: bool,
-    incomming_pub: VecDeque<Message>, // QoS 1
-    incomming_rec: VecDeque<Message>, // QoS 2
-    incomming_rel: VecDeque<PacketIdentifier>, // QoS 2
-    outgoing_ack: VecDeque<Message>, // QoS 1
-    outgoing_rec: VecDeque<Message>, // QoS 2
-    outgoing_comp: VecDeque<PacketIdentifier>, // QoS 2
-    await_suback: VecDeque<mqtt3::Subscribe>,
-    await_unsuback: VecDeque<mqtt3::Unsubscribe>,
+    ping_sent: Option<Instant>,
+    incomming: InFlight<Incomming>, // QoS 2
+    outgoing: InFlight<Outgoing>, // QoS 1 and QoS 2
+    await_suback: InFlight<(mqtt3::Subscribe, Completion<Vec<SubscribeResult>>)>,
+    await_unsuback: InFlight<mqtt3::Unsubscribe>,
+    // Messages received while waiting on the in-flight window
+    delivered: VecDeque<Message>,
+    // Messages published while disconnected
+    // with the completion of a `DeliveryToken`, if there is one
+    offline: VecDeque<(Message, Option<Completion<()>>)>,
+    // Publishes waited on with a `DeliveryToken`
+    delivery: BTreeMap<PacketIdentifier, Completion<()>>,
+    // Handlers by topic filter
+    router: Router,
     // Subscriptions
     subscriptions: HashMap<String, Subscription>,
+    metrics: Metrics,
 }
 
 impl<C: NetworkConnector> PubSub for Client<C> {
@@ -239,13 +554,21 @@ impl<C: NetworkConnector> PubSub for Client<C> {
         where T: ToTopicPath,
               P: ToPayload
     {
-        try!(self._publish(topic, payload, pubopt));
+        let message = try!(to_message(topic, payload, pubopt));
+        if self.state == ClientState::Disconnected {
+            return self._queue_offline(message, None);
+        }
+        while self._window_full(pubopt.qos()) {
+            try!(self._pump(None));
+        }
+        try!(self._publish(message));
         self._flush()
     }
 
-    fn subscribe<S: ToSubTopics>(&mut self, subs: S) -> Result<()> {
-        try!(self._subscribe(subs));
-        self._flush()
+    fn subscribe<S: ToSubTopics>(&mut self, subs: S) -> Result<SubscribeToken> {
+        let token = try!(self._subscribe(subs));
+        try!(self._flush());
+        Ok(token)
     }
 
     fn unsubscribe<U: ToUnSubTopics>(&mut self, unsubs: U) -> Result<()> {
@@ -253,9 +576,31 @@ impl<C: NetworkConnector> PubSub for Client<C> {
         self._flush()
     }
 
-    fn disconnect(mut self) -> Result<()> {
-        // self._disconnect();
-        self._flush()
+    fn disconnect(mut self, timeout: Option<Duration>) -> Result<Undelivered> {
+        // a lost connection is not worth reconnecting anymore
+        self.opts.reconnect = ReconnectMethod::ForeverDisconnect;
+
+        if let Some(timeout) = timeout {
+            let deadline = Instant::now() + timeout;
+            while self.state == ClientState::Connected && !self.outgoing.is_empty() {
+                match self._pump(Some(deadline)) {
+                    Ok(_) => (),
+                    Err(Error::Timeout) => break,
+                    Err(err) => {
+                        warn!("Disconnecting before in-flight messages are drained: {:?}", err);
+                        break;
+                    }
+                }
+            }
+        }
+
+        if self.state == ClientState::Connected {
+            if let Err(err) = self._disconnect() {
+                warn!("Unable to send DISCONNECT: {:?}", err);
+            }
+        }
+        self._unbind();
+        Ok(self._undelivered())
     }
 }
 
@@ -264,26 +609,33 @@ impl<C: NetworkConnector> Client<C> {
         Client {
             connector: BoxedConnector::new(self.connector),
             stream: Box::new(self.stream),
-            host_port: self.host_port,
+            endpoints: self.endpoints,
             state: self.state,
             opts: self.opts,
             session_present: self.session_present,
             last_flush: self.last_flush,
             last_pid: self.last_pid,
             await_ping: self.await_ping,
-            incomming_pub: self.incomming_pub,
-            incomming_rec: self.incomming_rec,
-            incomming_rel: self.incomming_rel,
-            outgoing_ack: self.outgoing_ack,
-            outgoing_rec: self.outgoing_rec,
-            outgoing_comp: self.outgoing_comp,
+            ping_sent: self.ping_sent,
+            incomming: self.incomming,
+            outgoing: self.outgoing,
             await_suback: self.await_suback,
             await_unsuback: self.await_unsuback,
+            delivered: self.delivered,
+            offline: self.offline,
+            delivery: self.delivery,
+            router: self.router,
             subscriptions: self.subscriptions,
+            metrics: self.metrics,
         }
     }
 
+    /// Waits for the next message, which is also passed to every handler
+    /// registered with `route` for a matching topic filter.
     pub fn await(&mut self) -> Result<Option<Message>> {
+        if let Some(message) = self.delivered.pop_front() {
+            return Ok(Some(message));
+        }
         loop {
             match self.accept() {
                 Ok(message) => {
@@ -293,17 +645,7 @@ impl<C: NetworkConnector> Client<C> {
                 }
                 Err(e) => {
                     match e {
-                        Error::Timeout => {
-                            if self.state == ClientState::Connected {
-                                if !self.await_ping {
-                                    let _ = self.ping();
-                                } else {
-                                    self._unbind();
-                                }
-                            } else {
-                                return Err(Error::Timeout);
-                            }
-                        }
+                        Error::Timeout => try!(self._keep_alive()),
                         _ => return Err(e),
                     }
                 }
@@ -315,21 +657,56 @@ impl<C: NetworkConnector> Client<C> {
     }
 
     pub fn accept(&mut self) -> Result<Option<Message>> {
+        self._accept(None)
+    }
+
+    fn _accept(&mut self, deadline: Option<Instant>) -> Result<Option<Message>> {
         match self.state {
             ClientState::Connected | ClientState::Handshake => {
                 // Don't forget to send PING packets in time
+                let mut read_timeout = None;
                 if let Some(keep_alive) = self.opts.keep_alive {
                     let elapsed = self.last_flush.elapsed();
                     if elapsed >= keep_alive {
                         return Err(Error::Timeout);
                     }
-                    try!(self.stream.set_read_timeout(Some(keep_alive - elapsed)));
+                    read_timeout = Some(keep_alive - elapsed);
                 }
-
-                match self.stream.read_packet() {
-                    Ok(packet) => {
-                        match self._parse_packet(packet) {
-                            Ok(message) => Ok(message),
+                if let Some(deadline) = deadline {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(Error::Timeout);
+                    }
+                    let left = deadline - now;
+                    read_timeout = Some(read_timeout.map_or(left, |t| cmp::min(t, left)));
+                }
+                try!(self.stream.set_read_timeout(read_timeout));
+
+                let (read, len) = {
+                    let mut reader = CountingReader::new(&mut self.stream);
+                    (reader.read_packet(), reader.count())
+                };
+                match read {
+                    Ok(mut packet) => {
+                        let verdict = intercept(&mut self.opts.interceptors,
+                                                &mut packet,
+                                                |interceptor, publish| {
+                                                    interceptor.incomming_publish(publish)
+                                                },
+                                                |interceptor, packet| interceptor.incomming(packet));
+                        let parsed = if verdict == Verdict::Veto {
+                            self._ack_vetoed(packet).map(|_| None)
+                        } else {
+                            metrics::count_received(&mut self.metrics, &packet, len);
+                            self._parse_packet(packet)
+                        };
+                        match parsed {
+                            Ok(message) => {
+                                if let Some(ref message) = message {
+                                    self.router.dispatch(message);
+                                }
+                                Ok(message)
+                            }
                             Err(err) => {
                                 match err {
                                     Error::ConnectionAbort => {
@@ -348,11 +725,7 @@ impl<C: NetworkConnector> Client<C> {
                         match err {
                             mqtt3::Error::UnexpectedEof => {
                                 error!("{:?}", err);
-                                if self._try_reconnect() {
-                                    Ok(None)
-                                } else {
-                                    Err(Error::Disconnected)
-                                }
+                                self._connection_lost()
                             }
                             mqtt3::Error::Io(e) => {
                                 match e.kind() {
@@ -364,12 +737,7 @@ impl<C: NetworkConnector> Client<C> {
                                     ErrorKind::ConnectionReset |
                                     ErrorKind::ConnectionAborted => {
                                         error!("{:?}", e);
-                                        self._unbind();
-                                        if self._try_reconnect() {
-                                            Ok(None)
-                                        } else {
-                                            Err(Error::Disconnected)
-                                        }
+                                        self._connection_lost()
                                     }
                                     _ => {
                                         error!("{:?}", e);
@@ -386,41 +754,57 @@ impl<C: NetworkConnector> Client<C> {
                     }
                 }
             }
-            ClientState::Disconnected => {
-                if self._try_reconnect() {
-                    Ok(None)
-                } else {
-                    Err(Error::Disconnected)
-                }
-            }
+            ClientState::Disconnected => self._try_reconnect().map(|_| None),
         }
     }
 
+    /// Same as `publish`, but fails with `Error::WouldBlock` instead of
+    /// waiting when the in-flight window is full.
+    pub fn try_publish<T, P>(&mut self, topic: T, payload: P, pubopt: PubOpt) -> Result<()>
+        where T: ToTopicPath,
+              P: ToPayload
+    {
+        let message = try!(to_message(topic, payload, pubopt));
+        if self.state == ClientState::Disconnected {
+            return self._queue_offline(message, None);
+        }
+        if self._window_full(pubopt.qos()) {
+            return Err(Error::WouldBlock);
+        }
+        try!(self._publish(message));
+        self._flush()
+    }
+
     pub fn reconnect(&mut self) -> Result<()> {
         if self.state == ClientState::Connected {
             warn!("mqttc is already connected");
             return Ok(());
         };
-        let stream = try!(self.opts._reconnect(&self.connector, &self.host_port));
-        self.stream = stream;
-        try!(self._handshake());
+        self.metrics.reconnect_attempts += 1;
+        let order = self.endpoints.reconnect_order();
+        try!(self._connect_any(&order));
 
         self._resubscribe();
+        try!(self._resume_session());
+        try!(self._publish_offline());
 
+        try!(self._flush());
+        self.metrics.reconnects += 1;
         Ok(())
     }
 
     pub fn ping(&mut self) -> Result<()> {
         debug!("       Pingreq");
         self.await_ping = true;
-        self._write_packet(&Packet::Pingreq);
+        self.ping_sent = Some(Instant::now());
+        try!(self._write_packet(&Packet::Pingreq));
         self._flush()
     }
 
     pub fn complete(&mut self, pid: PacketIdentifier) -> Result<()> {
-        let same_pid = self.incomming_rel.pop_back();
-        if same_pid == Some(pid) {
-            self._write_packet(&Packet::Pubcomp(pid));
+        if self.incomming.get(pid) == Some(&Incomming::Pubcomp) {
+            self.incomming.remove(pid);
+            try!(self._write_packet(&Packet::Pubcomp(pid)));
             try!(self._flush());
 
             if let Some(ref mut store) = self.opts.incomming_store {
@@ -446,12 +830,144 @@ impl<C: NetworkConnector> Client<C> {
         self.session_present
     }
 
+    /// The protocol spoken with the broker, which differs from the configured
+    /// one after a protocol fallback.
+    pub fn protocol(&self) -> Protocol {
+        self.opts.protocol
+    }
+
+    /// A snapshot of the traffic counters and queue depths of the client.
+    pub fn metrics(&self) -> Metrics {
+        let mut metrics = self.metrics.clone();
+        metrics.outgoing = self.outgoing.len();
+        metrics.incomming = self.incomming.len();
+        metrics.subscribes = self.await_suback.len();
+        metrics.unsubscribes = self.await_unsuback.len();
+        metrics.offline = self.offline.len();
+        metrics.since_last_flush = self.last_flush.elapsed();
+        metrics
+    }
+
+    /// Waits until the broker has answered the flow of `token`, handling
+    /// other incomming traffic meanwhile. Fails with `Error::Timeout` once
+    /// `timeout` has elapsed and with `Error::Disconnected` if the
+    /// connection was lost before the answer arrived.
+    pub fn wait<T: Clone>(&mut self, token: &Token<T>, timeout: Option<Duration>) -> Result<T> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self._wait(token, deadline)
+    }
+
+    /// Publishes a message and returns a token that completes once the
+    /// broker has acknowledged it: with PUBACK for QoS 1, PUBCOMP for QoS 2
+    /// and right away for QoS 0.
+    ///
+    /// While disconnected, the message goes to the offline queue like with
+    /// `publish`, and the token, which then has no `pid`, completes once it
+    /// is acknowledged after `reconnect`.
+    pub fn publish_token<T, P>(&mut self, topic: T, payload: P, pubopt: PubOpt) -> Result<DeliveryToken>
+        where T: ToTopicPath,
+              P: ToPayload
+    {
+        let message = try!(to_message(topic, payload, pubopt));
+        self._publish_token(message, None)
+    }
+
+    /// Publishes a message and blocks until the broker has acknowledged it,
+    /// failing with `Error::Timeout` if that takes longer than `timeout`.
+    pub fn publish_confirmed<T, P>(&mut self,
+                                   topic: T,
+                                   payload: P,
+                                   pubopt: PubOpt,
+                                   timeout: Duration)
+                                   -> Result<()>
+        where T: ToTopicPath,
+              P: ToPayload
+    {
+        let deadline = Some(Instant::now() + timeout);
+        let message = try!(to_message(topic, payload, pubopt));
+        let token = try!(self._publish_token(message, deadline));
+        self._wait(&token, deadline)
+    }
+
+    fn _wait<T: Clone>(&mut self, token: &Token<T>, deadline: Option<Instant>) -> Result<T> {
+        loop {
+            if let Some(result) = token.result() {
+                return Ok(result);
+            }
+            if token.is_failed() {
+                return Err(Error::ProtocolViolation);
+            }
+            if token.is_abandoned() {
+                return Err(Error::Disconnected);
+            }
+            try!(self._pump(deadline));
+        }
+    }
+
+    /// Registers a handler for messages whose topic matches `filter`, which
+    /// may contain `+` and `#` wildcards. Handlers run as soon as a message
+    /// is received, whether by `accept`, `await` or a call waiting for the
+    /// broker, before the message is returned.
+    pub fn route<T, F>(&mut self, filter: T, handler: F) -> Result<RouteId>
+        where T: ToTopicPath,
+              F: FnMut(&Message) + Send + 'static
+    {
+        let filter = try!(filter.to_topic_path()).path();
+        Ok(self.router.add(filter, Box::new(handler)))
+    }
+
+    pub fn unroute(&mut self, id: RouteId) -> bool {
+        self.router.remove(id)
+    }
+
+    /// The broker the client is connected, or was last connected, to.
+    pub fn active_endpoint(&self) -> &HostAndPort {
+        self.endpoints.active()
+    }
+
     fn _normalized(&self) -> bool {
         (self.state == ClientState::Connected) && (!self.await_ping) &&
-        (self.outgoing_ack.len() == 0) && (self.outgoing_rec.len() == 0) &&
-        (self.incomming_pub.len() == 0) && (self.incomming_rec.len() == 0) &&
-        (self.incomming_rel.len() == 0) && (self.await_suback.len() == 0) &&
-        (self.await_unsuback.len() == 0)
+        self.outgoing.is_empty() && self.incomming.is_empty() &&
+        self.await_suback.is_empty() && self.await_unsuback.is_empty()
+    }
+
+    /// Handles one incomming packet, keeping a received message for `await`.
+    /// Fails with `Error::Timeout` once the deadline has passed.
+    fn _pump(&mut self, deadline: Option<Instant>) -> Result<()> {
+        match self._accept(deadline) {
+            Ok(Some(message)) => {
+                self.delivered.push_back(message);
+                Ok(())
+            }
+            Ok(None) => Ok(()),
+            Err(Error::Timeout) => {
+                match deadline {
+                    Some(deadline) if Instant::now() >= deadline => Err(Error::Timeout),
+                    _ => self._keep_alive(),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn _keep_alive(&mut self) -> Result<()> {
+        if self.state == ClientState::Connected {
+            if !self.await_ping {
+                let _ = self.ping();
+            } else {
+                self._unbind();
+            }
+            Ok(())
+        } else {
+            Err(Error::Timeout)
+        }
+    }
+
+    fn _window_full(&self, qos: QoS) -> bool {
+        match (qos, self.opts.max_inflight) {
+            (QoS::AtMostOnce, _) | (_, None) => false,
+            (_, Some(max_inflight)) => self.outgoing.len() >= max_inflight,
+        }
     }
 
     fn _parse_packet(&mut self, packet: Packet) -> Result<Option<Message>> {
@@ -461,7 +977,13 @@ impl<C: NetworkConnector> Client<C> {
                 match packet {
                     Packet::Connack(ref connack) => {
                         if connack.code == ConnectReturnCode::Accepted {
-                            self.session_present = connack.session_present;
+                            // MQTT 3.1 has no session present flag, assume the
+                            // broker kept the session we asked for
+                            self.session_present = if protocol_level(self.opts.protocol) == 3 {
+                                !self.opts.clean_session
+                            } else {
+                                connack.session_present
+                            };
                             self.state = ClientState::Connected;
                             info!("    Connection accepted");
                             Ok(None)
@@ -480,90 +1002,126 @@ impl<C: NetworkConnector> Client<C> {
                         self._handle_message(message)
                     }
                     Packet::Puback(pid) => {
-                        if let Some(message) = self.outgoing_ack.pop_front() {
-                            if message.pid == Some(pid) {
+                        match self.outgoing.get(pid) {
+                            Some(&Outgoing::Puback(_)) => {
+                                self.outgoing.remove(pid);
+                                if let Some(completion) = self.delivery.remove(&pid) {
+                                    completion.complete(());
+                                }
+                                if let Some(ref mut store) = self.opts.outgoing_store {
+                                    try!(store.delete(pid));
+                                } else {
+                                    return Err(Error::OutgoingStorageAbsent);
+                                }
+                                try!(self._window_freed());
                                 Ok(None)
-                            } else {
-                                Err(Error::UnhandledPuback(pid))
                             }
-                        } else {
-                            Err(Error::UnhandledPuback(pid))
+                            _ => Err(Error::UnhandledPuback(pid)),
                         }
                     }
                     Packet::Pubrec(pid) => {
-                        if let Some(message) = self.outgoing_rec.pop_front() {
-                            if message.pid == Some(pid) {
-                                self._write_packet(&Packet::Pubrel(pid));
-                                try!(self._flush());
-
-                                self.outgoing_comp.push_back(pid);
+                        match self.outgoing.get(pid) {
+                            Some(&Outgoing::Pubrec(_)) => {
+                                // the message stays in the outgoing store until
+                                // PUBCOMP, marked as released so that a restarted
+                                // client sends PUBREL instead of the message
                                 if let Some(ref mut store) = self.opts.outgoing_store {
-                                    try!(store.delete(pid));
+                                    try!(store.release(pid));
                                 } else {
-                                    return Err(Error::IncommingStorageAbsent);
+                                    return Err(Error::OutgoingStorageAbsent);
                                 }
-
-                                Ok(None)
-                            } else {
-                                Err(Error::UnhandledPubrec(pid))
+                                self.outgoing.update(pid, Outgoing::Pubcomp);
                             }
-                        } else {
-                            Err(Error::UnhandledPubrec(pid))
+                            // PUBREC for a retransmitted PUBLISH, release it again
+                            Some(&Outgoing::Pubcomp) => (),
+                            _ => return Err(Error::UnhandledPubrec(pid)),
                         }
+                        try!(self._write_packet(&Packet::Pubrel(pid)));
+                        try!(self._flush());
+                        Ok(None)
                     }
                     Packet::Pubrel(pid) => {
-                        if let Some(message) = self.incomming_rec.pop_front() {
-                            if message.pid == Some(pid) {
+                        match self.incomming.get(pid) {
+                            Some(&Incomming::Pubrel) => {
                                 let message = if let Some(ref mut store) =
                                     self.opts
                                         .incomming_store {
+                                    // a restarted client must not deliver it again
+                                    try!(store.release(pid));
                                     try!(store.get(pid))
                                 } else {
                                     return Err(Error::IncommingStorageAbsent);
                                 };
-                                self.incomming_rel.push_back(pid);
+                                self.incomming.update(pid, Incomming::Pubcomp);
                                 Ok(Some(message))
-                            } else {
-                                Err(Error::UnhandledPubrel(pid))
                             }
-                        } else {
-                            Err(Error::UnhandledPubrel(pid))
+                            // already released, waiting for `complete`
+                            Some(&Incomming::Pubcomp) => Ok(None),
+                            Some(&Incomming::Delivered) => {
+                                self.incomming.remove(pid);
+                                try!(self._write_packet(&Packet::Pubcomp(pid)));
+                                try!(self._flush());
+                                if let Some(ref mut store) = self.opts.incomming_store {
+                                    try!(store.delete(pid));
+                                    Ok(None)
+                                } else {
+                                    Err(Error::IncommingStorageAbsent)
+                                }
+                            }
+                            None => Err(Error::UnhandledPubrel(pid)),
                         }
                     }
                     Packet::Pubcomp(pid) => {
-                        if let Some(_) = self.outgoing_comp.pop_front() {
-                            Ok(None)
-                        } else {
-                            Err(Error::UnhandledPubcomp(pid))
+                        match self.outgoing.get(pid) {
+                            Some(&Outgoing::Pubcomp) => {
+                                self.outgoing.remove(pid);
+                                if let Some(completion) = self.delivery.remove(&pid) {
+                                    completion.complete(());
+                                }
+                                if let Some(ref mut store) = self.opts.outgoing_store {
+                                    try!(store.delete(pid));
+                                } else {
+                                    return Err(Error::OutgoingStorageAbsent);
+                                }
+                                try!(self._window_freed());
+                                Ok(None)
+                            }
+                            _ => Err(Error::UnhandledPubcomp(pid)),
                         }
                     }
                     Packet::Suback(ref suback) => {
-                        if let Some(subscribe) = self.await_suback.pop_front() {
-                            if subscribe.pid == suback.pid {
-                                if subscribe.topics.len() == suback.return_codes.len() {
-                                    let iter = suback.return_codes.iter().zip(&subscribe.topics);
-                                    for (ref code, ref sub_topic) in iter {
-                                        match **code {
-                                            SubscribeReturnCodes::Success(qos) => {
-                                                let sub = Subscription {
-                                                    pid: subscribe.pid,
-                                                    topic_path: try!(sub_topic.topic_path
-                                                        .to_topic_path()),
-                                                    qos: qos,
-                                                };
-                                                self.subscriptions
-                                                    .insert(sub_topic.topic_path.clone(), sub);
-                                            }
-                                            SubscribeReturnCodes::Failure => {
-                                                // ignore subscription
-                                            }
+                        if let Some((subscribe, completion)) = self.await_suback.remove(suback.pid) {
+                            if subscribe.topics.len() == suback.return_codes.len() {
+                                let mut results = Vec::with_capacity(subscribe.topics.len());
+                                let iter = suback.return_codes.iter().zip(&subscribe.topics);
+                                for (ref code, ref sub_topic) in iter {
+                                    let granted = match **code {
+                                        SubscribeReturnCodes::Success(qos) => {
+                                            let sub = Subscription {
+                                                pid: subscribe.pid,
+                                                topic_path: try!(sub_topic.topic_path
+                                                    .to_topic_path()),
+                                                qos: qos,
+                                            };
+                                            self.subscriptions
+                                                .insert(sub_topic.topic_path.clone(), sub);
+                                            Some(qos)
                                         }
-                                    }
-                                    Ok(None)
-                                } else {
-                                    Err(Error::ProtocolViolation)
+                                        SubscribeReturnCodes::Failure => {
+                                            warn!("Subscription to {} refused", sub_topic.topic_path);
+                                            None
+                                        }
+                                    };
+                                    results.push(SubscribeResult {
+                                        topic: sub_topic.topic_path.clone(),
+                                        requested: sub_topic.qos,
+                                        granted: granted,
+                                    });
                                 }
+                                completion.complete(results);
+                                Ok(None)
                             } else {
+                                completion.fail();
                                 Err(Error::ProtocolViolation)
                             }
                         } else {
@@ -571,21 +1129,20 @@ impl<C: NetworkConnector> Client<C> {
                         }
                     }
                     Packet::Unsuback(pid) => {
-                        if let Some(unsubscribe) = self.await_unsuback.pop_front() {
-                            if unsubscribe.pid == pid {
-                                for topic in unsubscribe.topics.iter() {
-                                    self.subscriptions.remove(topic);
-                                }
-                                Ok(None)
-                            } else {
-                                Err(Error::ProtocolViolation)
+                        if let Some(unsubscribe) = self.await_unsuback.remove(pid) {
+                            for topic in unsubscribe.topics.iter() {
+                                self.subscriptions.remove(topic);
                             }
+                            Ok(None)
                         } else {
                             Err(Error::ProtocolViolation)
                         }
                     }
                     Packet::Pingresp => {
                         self.await_ping = false;
+                        if let Some(sent) = self.ping_sent.take() {
+                            self.metrics.ping_rtt = Some(sent.elapsed());
+                        }
                         Ok(None)
                     }
                     _ => Err(Error::UnrecognizedPacket),
@@ -595,6 +1152,33 @@ impl<C: NetworkConnector> Client<C> {
         }
     }
 
+    /// Acknowledges a PUBLISH vetoed by an interceptor, without delivering
+    /// it. A QoS 2 message is stored as released, so that only PUBCOMP is
+    /// sent on PUBREL, even by a restarted client.
+    fn _ack_vetoed(&mut self, packet: Packet) -> Result<()> {
+        let message = match packet {
+            Packet::Publish(publish) => try!(Message::from_pub(publish)),
+            _ => return Ok(()),
+        };
+        match (message.qos, message.pid) {
+            (QoS::AtLeastOnce, Some(pid)) => try!(self._write_packet(&Packet::Puback(pid))),
+            (QoS::ExactlyOnce, Some(pid)) => {
+                if !self.incomming.contains(pid) {
+                    if let Some(ref mut store) = self.opts.incomming_store {
+                        try!(store.put(message));
+                        try!(store.release(pid));
+                    } else {
+                        return Err(Error::IncommingStorageAbsent);
+                    }
+                    self.incomming.insert(pid, Incomming::Delivered);
+                }
+                try!(self._write_packet(&Packet::Pubrec(pid)))
+            }
+            _ => return Ok(()),
+        };
+        self._flush()
+    }
+
     fn _handle_message(&mut self, message: Message) -> Result<Option<Message>> {
         debug!("       Publish {} {} < {} bytes",
                message.qos.to_u8(),
@@ -603,27 +1187,27 @@ impl<C: NetworkConnector> Client<C> {
         match message.qos {
             QoS::AtMostOnce => Ok(Some(message)),
             QoS::AtLeastOnce => {
-                self.incomming_pub.push_back(message.clone());
                 let pid = message.pid.unwrap();
                 // debug!("        Puback {}", pid.0);
-                self._write_packet(&Packet::Puback(pid));
+                try!(self._write_packet(&Packet::Puback(pid)));
                 try!(self._flush());
-                // FIXME: can be repeated
-                let _ = self.incomming_pub.pop_front();
 
                 Ok(Some(message))
             }
             QoS::ExactlyOnce => {
-                self.incomming_rec.push_back(message.clone());
                 let pid = message.pid.unwrap();
 
-                if let Some(ref mut store) = self.opts.incomming_store {
-                    try!(store.put(message));
-                } else {
-                    return Err(Error::IncommingStorageAbsent);
+                // a redelivered PUBLISH is only acknowledged again
+                if !self.incomming.contains(pid) {
+                    if let Some(ref mut store) = self.opts.incomming_store {
+                        try!(store.put(message));
+                    } else {
+                        return Err(Error::IncommingStorageAbsent);
+                    }
+                    self.incomming.insert(pid, Incomming::Pubrel);
                 }
 
-                self._write_packet(&Packet::Pubrec(pid));
+                try!(self._write_packet(&Packet::Pubrec(pid)));
                 try!(self._flush());
 
                 Ok(None)
@@ -632,23 +1216,111 @@ impl<C: NetworkConnector> Client<C> {
     }
 
     fn _handshake(&mut self) -> Result<()> {
+        match self._try_handshake() {
+            Err(Error::ConnectionRefused(ConnectReturnCode::RefusedProtocolVersion))
+                if self.opts.protocol_fallback && protocol_level(self.opts.protocol) > 3 => {
+                let fallback = Protocol::MQIsdp(3);
+                try!(self.opts._check_client_id(fallback));
+                warn!("Protocol {:?} refused by {}, falling back to {:?}",
+                      self.opts.protocol, self.endpoints.active(), fallback);
+                // the broker closes the connection after refusing it
+                self._unbind();
+                self.stream = try!(self.opts._reconnect(&self.connector, self.endpoints.active()));
+                // keep the older protocol for later reconnects
+                self.opts.protocol = fallback;
+                self._try_handshake()
+            }
+            result => result,
+        }
+    }
+
+    /// Connects and completes the handshake with the first endpoint in
+    /// `order` that accepts both, so that a broker refusing CONNECT or
+    /// closing before CONNACK fails over like an unreachable one.
+    fn _connect_any(&mut self, order: &[usize]) -> Result<()> {
+        let mut last_err = Error::NoEndpoint;
+        for &idx in order {
+            let host_port = self.endpoints.get(idx).clone();
+            info!(" Connecting to {}", host_port);
+            match self.opts._reconnect(&self.connector, &host_port) {
+                Ok(stream) => {
+                    self.endpoints.set_active(idx);
+                    self.stream = stream;
+                    match self._handshake() {
+                        Ok(()) => return Ok(()),
+                        Err(err) => {
+                            warn!("Handshake with {} failed: {:?}", host_port, err);
+                            self._unbind();
+                            last_err = err;
+                        }
+                    }
+                }
+                Err(err) => {
+                    warn!("Unable to connect to {}: {:?}", host_port, err);
+                    last_err = err;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    fn _try_handshake(&mut self) -> Result<()> {
         self.state = ClientState::Handshake;
         // send CONNECT
         try!(self._connect());
-        // wait CONNACK
-        let _ = try!(self.await());
-        Ok(())
+        // wait CONNACK, in-flight messages are not ours to drain here
+        while self.state == ClientState::Handshake {
+            let _ = try!(self.accept());
+        }
+        if self.state == ClientState::Connected {
+            Ok(())
+        } else {
+            Err(Error::HandshakeFailed)
+        }
     }
 
-    fn _try_reconnect(&mut self) -> bool {
-        match self.opts.reconnect {
-            ReconnectMethod::ForeverDisconnect => false,
-            ReconnectMethod::ReconnectAfter(dur) => {
-                info!("  Reconnect in {} seconds", dur.as_secs());
-                thread::sleep(dur);
-                let _ = self.reconnect();
-                true
+    /// Reconnects according to `ReconnectMethod`, unless the connection was
+    /// lost while (re)connecting, which is left to the caller to retry.
+    fn _connection_lost(&mut self) -> Result<Option<Message>> {
+        let handshake = self.state == ClientState::Handshake;
+        self._unbind();
+        if handshake {
+            return Err(Error::Disconnected);
+        }
+        self._try_reconnect().map(|_| None)
+    }
+
+    /// Keeps calling `reconnect` until it succeeds or the reconnect method
+    /// gives up, in which case the last error is returned. `ReconnectAfter`
+    /// makes a single attempt, only a `Backoff` without `max_attempts`
+    /// retries forever.
+    fn _try_reconnect(&mut self) -> Result<()> {
+        let mut attempt = 0;
+        let mut delay = Duration::new(0, 0);
+        let mut rng = rand::thread_rng();
+        loop {
+            let (wait, max_attempts) = match self.opts.reconnect {
+                ReconnectMethod::ForeverDisconnect => return Err(Error::Disconnected),
+                ReconnectMethod::ReconnectAfter(dur) => (dur, Some(1)),
+                ReconnectMethod::Backoff(ref backoff) => {
+                    (backoff.delay(attempt, delay, &mut rng), backoff.max_attempts)
+                }
+            };
+
+            info!("  Reconnect in {:?}", wait);
+            thread::sleep(wait);
+            attempt += 1;
+            match self.reconnect() {
+                Ok(_) => return Ok(()),
+                Err(err) => {
+                    error!("  Reconnect attempt {} failed: {:?}", attempt, err);
+                    self._unbind();
+                    if max_attempts.map_or(false, |max| attempt >= max) {
+                        return Err(err);
+                    }
+                }
             }
+            delay = wait;
         }
     }
 
@@ -656,37 +1328,41 @@ impl<C: NetworkConnector> Client<C> {
         let connect = self.opts._generate_connect_packet();
         debug!("       Connect {}", connect.client_id);
         let packet = Packet::Connect(connect);
-        self._write_packet(&packet);
+        try!(self._write_packet(&packet));
         self._flush()
     }
 
-    fn _publish<T: ToTopicPath, P: ToPayload>(&mut self,
-                                              topic: T,
-                                              payload: P,
-                                              pubopt: PubOpt)
-                                              -> Result<()> {
-        let mut message = Message {
-            topic: try!(topic.to_topic_name()),
-            qos: pubopt.qos(),
-            retain: pubopt.is_retain(),
-            pid: None,
-            payload: payload.to_payload(),
-        };
-
+    fn _publish(&mut self, mut message: Message) -> Result<Option<PacketIdentifier>> {
+        // intercepted before the flow starts, so that nothing waits for a veto
+        let mut packet = Packet::Publish(message.to_pub(None, false));
+        let verdict = intercept(&mut self.opts.interceptors,
+                                &mut packet,
+                                |interceptor, publish| interceptor.outgoing_publish(publish),
+                                |interceptor, packet| interceptor.outgoing(packet));
+        if verdict == Verdict::Veto {
+            return Err(Error::Vetoed);
+        }
         match message.qos {
             QoS::AtMostOnce => (),
             QoS::AtLeastOnce => {
-                message.pid = Some(self._next_pid());
-                self.outgoing_ack.push_back(message.clone());
+                let pid = try!(self._next_pid());
+                message.pid = Some(pid);
+                if let Some(ref mut store) = self.opts.outgoing_store {
+                    try!(store.put(message.clone()));
+                } else {
+                    return Err(Error::OutgoingStorageAbsent);
+                }
+                self.outgoing.insert(pid, Outgoing::Puback(message.clone()));
             }
             QoS::ExactlyOnce => {
-                message.pid = Some(self._next_pid());
+                let pid = try!(self._next_pid());
+                message.pid = Some(pid);
                 if let Some(ref mut store) = self.opts.outgoing_store {
                     try!(store.put(message.clone()));
                 } else {
                     return Err(Error::OutgoingStorageAbsent);
                 }
-                self.outgoing_rec.push_back(message.clone());
+                self.outgoing.insert(pid, Outgoing::Pubrec(message.clone()));
             }
         }
 
@@ -694,32 +1370,142 @@ impl<C: NetworkConnector> Client<C> {
                message.qos.to_u8(),
                message.topic.path(),
                message.payload.len());
-        let packet = Packet::Publish(message.to_pub(None, false));
-        self._write_packet(&packet);
+        if let Packet::Publish(ref mut publish) = packet {
+            publish.pid = message.pid;
+        }
+        try!(self._send_packet(&packet));
+        metrics::count_published(&mut self.metrics, message.qos);
+        Ok(message.pid)
+    }
+
+    fn _publish_token(&mut self,
+                      message: Message,
+                      deadline: Option<Instant>)
+                      -> Result<DeliveryToken> {
+        if self.state == ClientState::Disconnected {
+            // the packet identifier is only known once it is published
+            let (token, completion) = token(None);
+            try!(self._queue_offline(message, Some(completion)));
+            return Ok(token);
+        }
+        while self._window_full(message.qos) {
+            try!(self._pump(deadline));
+        }
+        let pid = try!(self._publish(message));
+        try!(self._flush());
+
+        let (token, completion) = token(pid);
+        self._track_delivery(pid, completion);
+        Ok(token)
+    }
+
+    /// Completes `completion` once the broker acknowledged `pid`, or right
+    /// away for a QoS 0 publish.
+    fn _track_delivery(&mut self, pid: Option<PacketIdentifier>, completion: Completion<()>) {
+        match pid {
+            Some(pid) => {
+                self.delivery.insert(pid, completion);
+            }
+            None => completion.complete(()),
+        }
+    }
+
+    /// Keeps a message published while disconnected until `reconnect`.
+    fn _queue_offline(&mut self,
+                      message: Message,
+                      completion: Option<Completion<()>>)
+                      -> Result<()> {
+        let (capacity, overflow) = match self.opts.offline_queue {
+            Some(queue) => queue,
+            None => return Err(Error::Disconnected),
+        };
+        if self.offline.len() >= capacity {
+            match overflow {
+                OverflowPolicy::DropOldest if !self.offline.is_empty() => {
+                    let _ = self.offline.pop_front();
+                    warn!("Offline queue is full, dropped the oldest message");
+                }
+                OverflowPolicy::DropOldest | OverflowPolicy::DropNewest => {
+                    warn!("Offline queue is full, dropped the newest message");
+                    return Ok(());
+                }
+                OverflowPolicy::Error => return Err(Error::QueueFull),
+            }
+        }
+        debug!("       Publish {} {} queued while offline",
+               message.qos.to_u8(),
+               message.topic.path());
+        self.offline.push_back((message, completion));
+        Ok(())
+    }
+
+    /// Publishes the messages queued while offline, in order, as long as
+    /// the in-flight window has room. The rest is published as the broker
+    /// acknowledges in-flight messages.
+    fn _publish_offline(&mut self) -> Result<()> {
+        while let Some((message, completion)) = self.offline.pop_front() {
+            if self._window_full(message.qos) {
+                self.offline.push_front((message, completion));
+                break;
+            }
+            let inflight = self.outgoing.len();
+            match self._publish(message.clone()) {
+                Ok(pid) => {
+                    if let Some(completion) = completion {
+                        self._track_delivery(pid, completion);
+                    }
+                }
+                Err(Error::Vetoed) => {
+                    if let Some(completion) = completion {
+                        completion.fail();
+                    }
+                }
+                Err(err) => {
+                    if self.outgoing.len() == inflight {
+                        self.offline.push_front((message, completion));
+                    } else if let Some(completion) = completion {
+                        // once in flight, the message is retransmitted on reconnect
+                        let pid = self.last_pid;
+                        self.delivery.insert(pid, completion);
+                    }
+                    return Err(err);
+                }
+            }
+        }
         Ok(())
     }
 
-    fn _subscribe<S: ToSubTopics>(&mut self, subs: S) -> Result<()> {
+    /// Called when an acknowledgement made room in the in-flight window.
+    fn _window_freed(&mut self) -> Result<()> {
+        if self.offline.is_empty() {
+            return Ok(());
+        }
+        try!(self._publish_offline());
+        self._flush()
+    }
+
+    fn _subscribe<S: ToSubTopics>(&mut self, subs: S) -> Result<SubscribeToken> {
         let iter = try!(subs.to_subscribe_topics());
         let subscribe = mqtt3::Subscribe {
-            pid: self._next_pid(),
+            pid: try!(self._next_pid()),
             topics: iter.collect(),
         };
         debug!("     Subscribe {:?}", subscribe.topics);
-        self.await_suback.push_back(subscribe.clone());
-        self._write_packet(&Packet::Subscribe(subscribe));
-        Ok(())
+        let (token, completion) = token(Some(subscribe.pid));
+        self.await_suback.insert(subscribe.pid, (subscribe.clone(), completion));
+        try!(self._write_packet(&Packet::Subscribe(subscribe)));
+        Ok(token)
     }
 
     fn _unsubscribe<U: ToUnSubTopics>(&mut self, unsubs: U) -> Result<()> {
         let iter = try!(unsubs.to_unsubscribe_topics());
         let unsubscribe = mqtt3::Unsubscribe {
-            pid: self._next_pid(),
+            pid: try!(self._next_pid()),
             topics: iter.collect(),
         };
         debug!("   Unsubscribe {:?}", unsubscribe.topics);
-        self.await_unsuback.push_back(unsubscribe.clone());
-        self._write_packet(&Packet::Unsubscribe(unsubscribe));
+        self.await_unsuback.insert(unsubscribe.pid, unsubscribe.clone());
+        try!(self._write_packet(&Packet::Unsubscribe(unsubscribe)));
         Ok(())
     }
 
@@ -728,17 +1514,183 @@ impl<C: NetworkConnector> Client<C> {
             .values()
             .map(|sub| sub.to_subscribe_topic())
             .collect();
+        if subs.is_empty() {
+            return;
+        }
         let _ = self._subscribe(subs);
     }
 
-    fn _disconnect(&mut self) {
-        self._write_packet(&Packet::Disconnect);
+    /// Fills the in-flight tables from the stores, so that a restarted
+    /// client finishes what the previous process started. Messages are
+    /// loaded in the order they were stored, which is the order they are
+    /// retransmitted in.
+    fn _load_session(&mut self) {
+        if let Some(ref store) = self.opts.outgoing_store {
+            for message in store.iter() {
+                let pid = match message.pid {
+                    Some(pid) => pid,
+                    None => continue,
+                };
+                match message.qos {
+                    QoS::AtMostOnce => (),
+                    QoS::AtLeastOnce => {
+                        self.outgoing.insert(pid, Outgoing::Puback(message.clone()));
+                    }
+                    QoS::ExactlyOnce if store.is_released(pid) => {
+                        self.outgoing.insert(pid, Outgoing::Pubcomp);
+                    }
+                    QoS::ExactlyOnce => {
+                        self.outgoing.insert(pid, Outgoing::Pubrec(message.clone()));
+                    }
+                }
+            }
+        }
+        if let Some(ref store) = self.opts.incomming_store {
+            for message in store.iter() {
+                if let Some(pid) = message.pid {
+                    if store.is_released(pid) {
+                        self.incomming.insert(pid, Incomming::Delivered);
+                    } else {
+                        self.incomming.insert(pid, Incomming::Pubrel);
+                    }
+                }
+            }
+        }
+        if !self.outgoing.is_empty() || !self.incomming.is_empty() {
+            info!("  Loaded {} outgoing and {} incomming messages",
+                  self.outgoing.len(),
+                  self.incomming.len());
+        }
+    }
+
+    /// Brings the in-flight state in line with the session the broker
+    /// reported in CONNACK.
+    fn _resume_session(&mut self) -> Result<()> {
+        if self.opts.clean_session {
+            return self._discard_session();
+        }
+        if !self.session_present {
+            // a fresh session will never release what the old one received
+            let pids: Vec<PacketIdentifier> = self.incomming
+                .ordered()
+                .into_iter()
+                .filter(|&(_, incomming)| *incomming != Incomming::Pubcomp)
+                .map(|(pid, _)| pid)
+                .collect();
+            for pid in pids {
+                self.incomming.remove(pid);
+                if let Some(ref mut store) = self.opts.incomming_store {
+                    try!(store.delete(pid));
+                }
+            }
+        }
+        let dup = self.session_present;
+        self._retransmit(dup)
     }
 
-    #[inline]
-    fn _write_packet(&mut self, packet: &Packet) {
+    fn _discard_session(&mut self) -> Result<()> {
+        if !self.outgoing.is_empty() {
+            warn!("Dropping {} in-flight messages of a clean session", self.outgoing.len());
+        }
+        self.outgoing.clear();
+        self.delivery.clear();
+        self.incomming.clear();
+        if let Some(ref mut store) = self.opts.outgoing_store {
+            try!(store.clear());
+        }
+        if let Some(ref mut store) = self.opts.incomming_store {
+            try!(store.clear());
+        }
+        Ok(())
+    }
+
+    /// Resends everything the broker has not acknowledged yet, in original
+    /// order: pending PUBLISH packets, with the DUP flag set if the broker
+    /// has seen them before, and PUBREL for QoS 2 flows that were already
+    /// received.
+    fn _retransmit(&mut self, dup: bool) -> Result<()> {
+        let packets: Vec<(PacketIdentifier, Packet)> = self.outgoing
+            .ordered()
+            .into_iter()
+            .map(|(pid, outgoing)| {
+                let packet = match *outgoing {
+                    Outgoing::Puback(ref message) |
+                    Outgoing::Pubrec(ref message) => Packet::Publish(message.to_pub(None, dup)),
+                    Outgoing::Pubcomp => Packet::Pubrel(pid),
+                };
+                (pid, packet)
+            })
+            .collect();
+        for (pid, packet) in packets {
+            debug!("    Retransmit {:?}", packet);
+            if !try!(self._write_packet(&packet)) {
+                try!(self._abandon(pid));
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives up the outgoing flow of `pid`, failing its `DeliveryToken`.
+    fn _abandon(&mut self, pid: PacketIdentifier) -> Result<()> {
+        warn!("Giving up the vetoed retransmission of {:?}", pid);
+        self.outgoing.remove(pid);
+        if let Some(completion) = self.delivery.remove(&pid) {
+            completion.fail();
+        }
+        if let Some(ref mut store) = self.opts.outgoing_store {
+            try!(store.delete(pid));
+        }
+        Ok(())
+    }
+
+    fn _disconnect(&mut self) -> Result<()> {
+        debug!("    Disconnect");
+        try!(self._write_packet(&Packet::Disconnect));
+        self._flush()
+    }
+
+    fn _undelivered(&self) -> Undelivered {
+        let mut undelivered = Undelivered::default();
+        for (pid, outgoing) in self.outgoing.ordered() {
+            match *outgoing {
+                Outgoing::Puback(ref message) |
+                Outgoing::Pubrec(ref message) => undelivered.messages.push(message.clone()),
+                Outgoing::Pubcomp => undelivered.unreleased.push(pid),
+            }
+        }
+        undelivered.messages.extend(self.offline.iter().map(|&(ref message, _)| message.clone()));
+        undelivered
+    }
+
+    /// Writes `packet` once the interceptors have seen it. Returns whether
+    /// it was written, `false` if it was vetoed.
+    fn _write_packet(&mut self, packet: &Packet) -> Result<bool> {
+        let mut intercepted;
+        let packet = if self.opts.interceptors.is_empty() {
+            packet
+        } else {
+            intercepted = packet.clone();
+            let verdict = intercept(&mut self.opts.interceptors,
+                                    &mut intercepted,
+                                    |interceptor, publish| interceptor.outgoing_publish(publish),
+                                    |interceptor, packet| interceptor.outgoing(packet));
+            if verdict == Verdict::Veto {
+                return Ok(false);
+            }
+            &intercepted
+        };
+        try!(self._send_packet(packet));
+        Ok(true)
+    }
+
+    /// Writes `packet` as is, without running the interceptors.
+    fn _send_packet(&mut self, packet: &Packet) -> Result<()> {
         trace!("{:?}", packet);
-        self.stream.write_packet(&packet).unwrap();
+        let mut data = Vec::new();
+        try!(data.write_packet(packet));
+        try!(self.stream.write_all(&data));
+        metrics::count_sent(&mut self.metrics, packet, data.len());
+        Ok(())
     }
 
     fn _flush(&mut self) -> Result<()> {
@@ -753,22 +1705,102 @@ impl<C: NetworkConnector> Client<C> {
         self.await_unsuback.clear();
         self.await_suback.clear();
         self.await_ping = false;
+        self.ping_sent = None;
         self.state = ClientState::Disconnected;
         info!("  Disconnected {}", self.opts.client_id.clone().unwrap());
     }
 
-    #[inline]
-    fn _next_pid(&mut self) -> PacketIdentifier {
-        self.last_pid = self.last_pid.next();
-        self.last_pid
+    /// Allocates the next packet identifier, skipping zero and identifiers
+    /// that are still in flight.
+    fn _next_pid(&mut self) -> Result<PacketIdentifier> {
+        let mut pid = self.last_pid;
+        for _ in 0..u16::max_value() {
+            pid = PacketIdentifier(pid.0.wrapping_add(1));
+            if pid.0 == 0 {
+                continue;
+            }
+            if !self.outgoing.contains(pid) && !self.await_suback.contains(pid) &&
+               !self.await_unsuback.contains(pid) {
+                self.last_pid = pid;
+                return Ok(pid);
+            }
+        }
+        Err(Error::WouldBlock)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::ClientOptions;
+    use std::io::Cursor;
+    use std::time::Duration;
+    use super::{Client, ClientOptions};
+    use error::{Error, Result};
     use netopt::mock::MockConnector;
     use url::{Host, HostAndPort};
+    use std::sync::{Arc, Mutex};
+    use mqtt3::{self, MqttRead, Message, Packet, PacketIdentifier, Publish, QoS, SubscribeTopic,
+                SubscribeReturnCodes, ConnectReturnCode, ToTopicPath, Protocol};
+    use store::{MemoryStorage, Store};
+    use netopt::mock::ScriptedConnector;
+    use netopt::FaultConnector;
+    use {PubSub, PubOpt, ReconnectMethod, Backoff, ClientState, OverflowPolicy, PacketType,
+         Traffic, Interceptor, Verdict};
+
+    fn host(name: &str) -> HostAndPort {
+        HostAndPort { host: Host::Domain(name.to_string()), port: 1883 }
+    }
+
+    fn is_connect(packet: &Packet) -> bool {
+        match *packet {
+            Packet::Connect(_) => true,
+            _ => false,
+        }
+    }
+
+    fn connack(session_present: bool) -> Packet {
+        Packet::Connack(mqtt3::Connack {
+            session_present: session_present,
+            code: ConnectReturnCode::Accepted,
+        })
+    }
+
+    /// A mock broker accepting the first connection without a session.
+    fn broker() -> ScriptedConnector {
+        ScriptedConnector::new().expect_with("CONNECT", is_connect).send(connack(false))
+    }
+
+    /// Connects to `script` as the broker on localhost.
+    fn scripted_client(options: ClientOptions,
+                       script: &ScriptedConnector)
+                       -> Result<Client<ScriptedConnector>> {
+        options.connect_with(script.clone(), &host("localhost"))
+    }
+
+    fn message(topic: &str, payload: &str, qos: QoS) -> Message {
+        Message {
+            topic: topic.to_topic_name().unwrap(),
+            qos: qos,
+            retain: false,
+            pid: None,
+            payload: Arc::new(payload.as_bytes().to_vec()),
+        }
+    }
+
+    fn publish(message: &Message, pid: Option<u16>, dup: bool) -> Packet {
+        let mut message = message.clone();
+        message.pid = pid.map(PacketIdentifier);
+        Packet::Publish(message.to_pub(None, dup))
+    }
+
+    #[cfg(not(any(feature = "ssl", feature = "rustls")))]
+    #[test]
+    fn client_tls_unavailable_test() {
+        match ClientOptions::new().connect_str("mqtts://localhost") {
+            Err(Error::TlsUnavailable) => (),
+            Err(err) => panic!("expected TlsUnavailable, got {:?}", err),
+            Ok(_) => panic!("expected TlsUnavailable"),
+        }
+    }
 
     #[test]
     fn client_connect_test() {
@@ -776,7 +1808,675 @@ mod test {
         let options = ClientOptions::new();
         let connector = MockConnector::with_read_data(mock_data);
         // Connect and create MQTT client
-        let host_port = HostAndPort { host: Host::Domain("localhost".to_string()), port: 1883 };
-        let _client = options.connect_with(connector, &host_port).unwrap();
+        let _client = options.connect_with(connector, &host("localhost")).unwrap();
+    }
+
+    #[test]
+    fn client_metrics_test() {
+        let script = broker()
+            .expect(publish(&message("a/b", "payload", QoS::AtMostOnce), None, false))
+            .expect(publish(&message("a/b", "payload", QoS::AtLeastOnce), Some(1), false));
+        let mut client = scripted_client(ClientOptions::new(), &script).unwrap();
+        client.publish("a/b", "payload", PubOpt::at_most_once()).unwrap();
+        client.publish("a/b", "payload", PubOpt::at_least_once()).unwrap();
+        script.assert_done();
+
+        let metrics = client.metrics();
+        assert_eq!(metrics.sent[&PacketType::Connect].packets, 1);
+        assert_eq!(metrics.sent[&PacketType::Publish].packets, 2);
+        assert_eq!(metrics.received[&PacketType::Connack], Traffic { packets: 1, bytes: 4 });
+        assert_eq!(metrics.publishes_sent, [1, 1, 0]);
+        assert_eq!(metrics.outgoing, 1);
+        assert_eq!(metrics.reconnect_attempts, 0);
+    }
+
+    struct TenantInterceptor;
+
+    impl Interceptor for TenantInterceptor {
+        fn outgoing_publish(&mut self, publish: &mut Publish) -> Verdict {
+            if publish.topic_name.starts_with("secret/") {
+                return Verdict::Veto;
+            }
+            publish.topic_name = format!("tenant/{}", publish.topic_name);
+            Verdict::Pass
+        }
+
+        fn incomming_publish(&mut self, publish: &mut Publish) -> Verdict {
+            if publish.topic_name.starts_with("secret/") {
+                Verdict::Veto
+            } else {
+                Verdict::Pass
+            }
+        }
+    }
+
+    /// Records the packets but PUBLISH sent by the client.
+    struct ControlInterceptor(Arc<Mutex<Vec<Packet>>>);
+
+    impl Interceptor for ControlInterceptor {
+        fn outgoing(&mut self, packet: &mut Packet) {
+            self.0.lock().unwrap().push(packet.clone());
+        }
+    }
+
+    #[test]
+    fn client_interceptor_test() {
+        let script = broker()
+            .send(publish(&message("secret/a", "payload", QoS::AtMostOnce), None, false))
+            .send(publish(&message("a/b", "payload", QoS::AtMostOnce), None, false))
+            .expect(publish(&message("tenant/a/b", "payload", QoS::AtMostOnce), None, false))
+            .expect(Packet::Pingreq);
+        let control = Arc::new(Mutex::new(Vec::new()));
+        let mut options = ClientOptions::new();
+        options.add_interceptor(TenantInterceptor);
+        options.add_interceptor(ControlInterceptor(control.clone()));
+        let mut client = scripted_client(options, &script).unwrap();
+
+        assert!(client.accept().unwrap().is_none());
+        let message = client.accept().unwrap().unwrap();
+        assert_eq!(message.topic.path(), "a/b");
+
+        match client.publish("secret/b", "payload", PubOpt::at_most_once()) {
+            Err(Error::Vetoed) => (),
+            result => panic!("expected Vetoed, got {:?}", result),
+        }
+        client.publish("a/b", "payload", PubOpt::at_most_once()).unwrap();
+        client.ping().unwrap();
+        assert!(client.await_ping);
+        script.assert_done();
+
+        let control = control.lock().unwrap();
+        assert_eq!(control.len(), 2);
+        assert!(is_connect(&control[0]));
+        assert_eq!(control[1], Packet::Pingreq);
+
+        // vetoed packets are not counted either way
+        let metrics = client.metrics();
+        assert_eq!(metrics.received[&PacketType::Publish].packets, 1);
+        assert_eq!(metrics.sent[&PacketType::Publish].packets, 1);
+        assert_eq!(metrics.publishes_received, [1, 0, 0]);
+        assert_eq!(metrics.publishes_sent, [1, 0, 0]);
+    }
+
+    #[test]
+    fn client_outgoing_veto_test() {
+        let script = broker()
+            .expect(publish(&message("tenant/a/b", "payload", QoS::AtLeastOnce), Some(1), false));
+        let mut options = ClientOptions::new();
+        options.set_max_inflight(1);
+        options.add_interceptor(TenantInterceptor);
+        let mut client = scripted_client(options, &script).unwrap();
+
+        match client.publish_token("secret/b", "payload", PubOpt::at_least_once()) {
+            Err(Error::Vetoed) => (),
+            result => panic!("expected Vetoed, got {:?}", result),
+        }
+        // the veto took neither a packet identifier nor room in the window
+        assert!(!client._window_full(QoS::AtLeastOnce));
+        assert!(client.outgoing.is_empty());
+        assert!(client.delivery.is_empty());
+        assert!(client.opts.outgoing_store.as_ref().unwrap().is_empty());
+
+        let token = client.publish_token("a/b", "payload", PubOpt::at_least_once()).unwrap();
+        assert_eq!(token.pid(), Some(PacketIdentifier(1)));
+        script.assert_done();
+    }
+
+    #[test]
+    fn client_incomming_veto_test() {
+        let script = broker()
+            .send(publish(&message("secret/a", "payload", QoS::AtLeastOnce), Some(1), false))
+            .expect(Packet::Puback(PacketIdentifier(1)))
+            .send(publish(&message("secret/b", "payload", QoS::ExactlyOnce), Some(2), false))
+            .expect(Packet::Pubrec(PacketIdentifier(2)))
+            .send(Packet::Pubrel(PacketIdentifier(2)))
+            .expect(Packet::Pubcomp(PacketIdentifier(2)));
+        let mut options = ClientOptions::new();
+        options.add_interceptor(TenantInterceptor);
+        let mut client = scripted_client(options, &script).unwrap();
+        client.route("secret/#", |_: &Message| panic!("vetoed message dispatched")).unwrap();
+
+        // acknowledged, but neither delivered nor dispatched
+        assert_eq!(client.accept().unwrap(), None);
+        assert_eq!(client.accept().unwrap(), None);
+        assert_eq!(client.accept().unwrap(), None);
+        assert!(client.incomming.is_empty());
+        assert!(client.opts.incomming_store.as_ref().unwrap().is_empty());
+        script.assert_done();
+    }
+
+    #[test]
+    fn client_retransmit_test() {
+        let first = message("a/b", "first", QoS::AtLeastOnce);
+        let second = message("a/b", "second", QoS::ExactlyOnce);
+        let script = broker()
+            .expect(publish(&first, Some(1), false))
+            .expect(publish(&second, Some(2), false))
+            .expect_with("CONNECT", is_connect)
+            .send(connack(true))
+            .expect(publish(&first, Some(1), true))
+            .expect(publish(&second, Some(2), true));
+        let mut options = ClientOptions::new();
+        options.set_clean_session(false);
+        let mut client = scripted_client(options, &script).unwrap();
+
+        client.publish("a/b", "first", PubOpt::at_least_once()).unwrap();
+        client.publish("a/b", "second", PubOpt::exactly_once()).unwrap();
+        client.terminate();
+        client.reconnect().unwrap();
+        script.assert_done();
+    }
+
+    #[test]
+    fn client_out_of_order_ack_test() {
+        let mock_data = vec![0b00100000, 0x02, 0x00, 0x00, // CONNACK
+                             0b01000000, 0x02, 0x00, 0x02, // PUBACK 2
+                             0b01000000, 0x02, 0x00, 0x01]; // PUBACK 1
+        let options = ClientOptions::new();
+        let connector = MockConnector::with_read_data(mock_data);
+        let mut client = options.connect_with(connector, &host("localhost")).unwrap();
+
+        client.publish("a/b", "first", PubOpt::at_least_once()).unwrap();
+        client.publish("a/b", "second", PubOpt::at_least_once()).unwrap();
+        assert!(client.await().unwrap().is_none());
+        assert!(client.outgoing.is_empty());
+    }
+
+    #[test]
+    fn client_inflight_window_test() {
+        let mock_data = vec![0b00100000, 0x02, 0x00, 0x00, // CONNACK
+                             0b01000000, 0x02, 0x00, 0x01]; // PUBACK 1
+        let mut options = ClientOptions::new();
+        options.set_max_inflight(1);
+        let connector = MockConnector::with_read_data(mock_data);
+        let mut client = options.connect_with(connector, &host("localhost")).unwrap();
+
+        client.publish("a/b", "first", PubOpt::at_least_once()).unwrap();
+        match client.try_publish("a/b", "second", PubOpt::at_least_once()) {
+            Err(Error::WouldBlock) => (),
+            result => panic!("expected WouldBlock, got {:?}", result),
+        }
+        client.try_publish("a/b", "third", PubOpt::at_most_once()).unwrap();
+        // waits for PUBACK 1, then reuses the window
+        client.publish("a/b", "second", PubOpt::at_least_once()).unwrap();
+        assert!(client.outgoing.contains(PacketIdentifier(2)));
+        assert_eq!(client.outgoing.len(), 1);
+
+        // a zero window would block every publish, it means unlimited
+        let mut options = ClientOptions::new();
+        options.set_max_inflight(0);
+        assert_eq!(options.max_inflight, None);
+    }
+
+    #[test]
+    fn client_next_pid_test() {
+        let mock_data = vec![0b00100000, 0x02, 0x00, 0x00];
+        let options = ClientOptions::new();
+        let connector = MockConnector::with_read_data(mock_data);
+        let mut client = options.connect_with(connector, &host("localhost")).unwrap();
+
+        client.last_pid = PacketIdentifier(65534);
+        client.publish("a/b", "in flight", PubOpt::at_least_once()).unwrap();
+        client.publish("a/b", "in flight", PubOpt::at_least_once()).unwrap();
+        assert!(client.outgoing.contains(PacketIdentifier(65535)));
+        assert!(client.outgoing.contains(PacketIdentifier(1)));
+
+        client.last_pid = PacketIdentifier(65534);
+        assert_eq!(client._next_pid().unwrap(), PacketIdentifier(2));
+    }
+
+    #[test]
+    fn client_disconnect_test() {
+        let mock_data = vec![0b00100000, 0x02, 0x00, 0x00, // CONNACK
+                             0b01000000, 0x02, 0x00, 0x01]; // PUBACK 1
+        let options = ClientOptions::new();
+        let connector = MockConnector::with_read_data(mock_data);
+        let mut client = options.connect_with(connector, &host("localhost")).unwrap();
+
+        client.publish("a/b", "acked", PubOpt::at_least_once()).unwrap();
+        client.publish("a/b", "lost", PubOpt::at_least_once()).unwrap();
+        let undelivered = client.disconnect(Some(Duration::from_millis(100))).unwrap();
+        assert_eq!(undelivered.messages.len(), 1);
+        assert_eq!(&undelivered.messages[0].payload[..], b"lost");
+        assert!(undelivered.unreleased.is_empty());
+    }
+
+    #[test]
+    fn client_reconnect_attempts_test() {
+        let mock_data = vec![0b00100000, 0x02, 0x00, 0x00];
+        let options = ClientOptions::new();
+        let connector = MockConnector::with_read_data(mock_data);
+        let mut client = options.connect_with(connector, &host("localhost")).unwrap();
+
+        // the broker goes away and never answers a CONNECT again
+        client.connector = MockConnector::new();
+        client.terminate();
+        let backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(4))
+            .with_max_attempts(3);
+        client.set_reconnect(ReconnectMethod::Backoff(backoff));
+        match client.accept() {
+            Err(Error::Disconnected) => (),
+            result => panic!("expected Disconnected, got {:?}", result),
+        }
+        assert_eq!(client.state, ClientState::Disconnected);
+    }
+
+    #[test]
+    fn client_session_recovery_test() {
+        let message = Message {
+            topic: "a/b".to_topic_name().unwrap(),
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            pid: Some(PacketIdentifier(7)),
+            payload: Arc::new(b"stored".to_vec()),
+        };
+        let mut store = MemoryStorage::new();
+        store.put(message.clone()).unwrap();
+
+        let script = ScriptedConnector::new()
+            .expect_with("CONNECT", is_connect)
+            .send(connack(true))
+            .expect(publish(&message, Some(7), true));
+        let mut options = ClientOptions::new();
+        options.set_clean_session(false);
+        options.set_outgoing_store(store);
+        let mut client = scripted_client(options, &script).unwrap();
+        assert!(client.session_present());
+        script.assert_done();
+        // a new publish doesn't reuse the recovered identifier
+        client.last_pid = PacketIdentifier(6);
+        assert_eq!(client._next_pid().unwrap(), PacketIdentifier(8));
+    }
+
+    #[test]
+    fn client_released_session_test() {
+        let message = |pid, qos| {
+            Message {
+                topic: "a/b".to_topic_name().unwrap(),
+                qos: qos,
+                retain: false,
+                pid: Some(PacketIdentifier(pid)),
+                payload: Arc::new(b"stored".to_vec()),
+            }
+        };
+        // published before the identifiers wrapped, PUBREL was already sent
+        let mut outgoing = MemoryStorage::new();
+        outgoing.put(message(65535, QoS::ExactlyOnce)).unwrap();
+        outgoing.release(PacketIdentifier(65535)).unwrap();
+        outgoing.put(message(1, QoS::AtLeastOnce)).unwrap();
+        // handed to the user, who never completed it
+        let mut incomming = MemoryStorage::new();
+        incomming.put(message(9, QoS::ExactlyOnce)).unwrap();
+        incomming.release(PacketIdentifier(9)).unwrap();
+
+        let mock_data = vec![0b00100000, 0x02, 0x01, 0x00, // CONNACK
+                             0b01100010, 0x02, 0x00, 0x09]; // PUBREL
+        let mut options = ClientOptions::new();
+        options.set_clean_session(false);
+        options.set_outgoing_store(outgoing);
+        options.set_incomming_store(incomming);
+        let connector = MockConnector::with_read_data(mock_data);
+        let mut client = options.connect_with(connector, &host("localhost")).unwrap();
+        assert_eq!(client.accept().unwrap(), None);
+
+        let mut cursor = Cursor::new(client.stream.drain_write_data());
+        match cursor.read_packet().unwrap() {
+            Packet::Connect(_) => (),
+            packet => panic!("expected CONNECT, got {:?}", packet),
+        }
+        assert_eq!(cursor.read_packet().unwrap(), Packet::Pubrel(PacketIdentifier(65535)));
+        match cursor.read_packet().unwrap() {
+            Packet::Publish(ref publish) => assert_eq!(publish.pid, Some(PacketIdentifier(1))),
+            packet => panic!("expected PUBLISH, got {:?}", packet),
+        }
+        assert_eq!(cursor.read_packet().unwrap(), Packet::Pubcomp(PacketIdentifier(9)));
+        assert!(client.opts.incomming_store.as_ref().unwrap().is_empty());
+    }
+
+    #[test]
+    fn client_offline_queue_test() {
+        let script = broker()
+            .expect_with("CONNECT", is_connect)
+            .send(connack(false))
+            .expect(publish(&message("a/b", "second", QoS::AtLeastOnce), Some(1), false))
+            .expect(publish(&message("a/b", "third", QoS::AtMostOnce), None, false));
+        let mut options = ClientOptions::new();
+        options.set_offline_queue(2, OverflowPolicy::DropOldest);
+        let mut client = scripted_client(options, &script).unwrap();
+
+        client.terminate();
+        client.publish("a/b", "first", PubOpt::at_most_once()).unwrap();
+        client.publish("a/b", "second", PubOpt::at_least_once()).unwrap();
+        client.publish("a/b", "third", PubOpt::at_most_once()).unwrap();
+        client.opts.offline_queue = Some((2, OverflowPolicy::Error));
+        match client.publish("a/b", "fourth", PubOpt::at_most_once()) {
+            Err(Error::QueueFull) => (),
+            result => panic!("expected QueueFull, got {:?}", result),
+        }
+        client.reconnect().unwrap();
+        assert!(client.offline.is_empty());
+        script.assert_done();
+    }
+
+    #[test]
+    fn client_offline_window_test() {
+        let mock_data = vec![0b00100000, 0x02, 0x00, 0x00, // CONNACK
+                             0b01000000, 0x02, 0x00, 0x01]; // PUBACK
+        let mut options = ClientOptions::new();
+        options.set_offline_queue(4, OverflowPolicy::Error);
+        options.set_max_inflight(1);
+        let connector = MockConnector::with_read_data(mock_data);
+        let mut client = options.connect_with(connector, &host("localhost")).unwrap();
+
+        client.terminate();
+        client.publish("a/b", "first", PubOpt::at_least_once()).unwrap();
+        client.publish("a/b", "second", PubOpt::at_least_once()).unwrap();
+        client.reconnect().unwrap();
+        assert_eq!(client.outgoing.len(), 1);
+        assert_eq!(client.offline.len(), 1);
+
+        assert_eq!(client.accept().unwrap(), None);
+        assert_eq!(client.outgoing.len(), 1);
+        assert!(client.offline.is_empty());
+        let mut cursor = Cursor::new(client.stream.drain_write_data());
+        match cursor.read_packet().unwrap() {
+            Packet::Connect(_) => (),
+            packet => panic!("expected CONNECT, got {:?}", packet),
+        }
+        for &(pid, payload) in [(1, "first"), (2, "second")].iter() {
+            match cursor.read_packet().unwrap() {
+                Packet::Publish(ref publish) => {
+                    assert_eq!(publish.pid, Some(PacketIdentifier(pid)));
+                    assert_eq!(&publish.payload[..], payload.as_bytes());
+                }
+                packet => panic!("expected PUBLISH, got {:?}", packet),
+            }
+        }
+    }
+
+    #[test]
+    fn client_offline_token_test() {
+        let script = broker()
+            .expect_with("CONNECT", is_connect)
+            .send(connack(false))
+            .expect(publish(&message("a/b", "queued", QoS::AtLeastOnce), Some(1), false))
+            .send(Packet::Puback(PacketIdentifier(1)));
+        let mut options = ClientOptions::new();
+        options.set_offline_queue(4, OverflowPolicy::Error);
+        let mut client = scripted_client(options, &script).unwrap();
+
+        client.terminate();
+        let token = client.publish_token("a/b", "queued", PubOpt::at_least_once()).unwrap();
+        assert_eq!(client.offline.len(), 1);
+        assert_eq!(token.result(), None);
+
+        client.reconnect().unwrap();
+        assert!(client.offline.is_empty());
+        assert_eq!(token.result(), None);
+        assert_eq!(client.accept().unwrap(), None);
+        assert_eq!(token.result(), Some(()));
+        assert!(client.delivery.is_empty());
+    }
+
+    #[test]
+    fn client_route_test() {
+        let message = message("sensors/1/temp", "21", QoS::AtMostOnce);
+        let script = broker().send(publish(&message, None, false));
+        let mut client = scripted_client(ClientOptions::new(), &script).unwrap();
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = received.clone();
+        client.route("sensors/+/temp", move |message: &Message| {
+            sink.lock().unwrap().push(message.topic.path());
+        }).unwrap();
+        client.route("logs/#", |_: &Message| panic!("unexpected message")).unwrap();
+
+        assert_eq!(client.accept().unwrap(), Some(message));
+        assert_eq!(*received.lock().unwrap(), vec!["sensors/1/temp".to_string()]);
+    }
+
+    #[test]
+    fn client_subscribe_token_test() {
+        let topics = vec![
+            SubscribeTopic { topic_path: "a/+".to_string(), qos: QoS::ExactlyOnce },
+            SubscribeTopic { topic_path: "secret/#".to_string(), qos: QoS::AtMostOnce },
+        ];
+        let script = broker()
+            .expect(Packet::Subscribe(mqtt3::Subscribe {
+                pid: PacketIdentifier(1),
+                topics: topics.clone(),
+            }))
+            .send(Packet::Suback(mqtt3::Suback {
+                pid: PacketIdentifier(1),
+                return_codes: vec![SubscribeReturnCodes::Success(QoS::AtLeastOnce),
+                                   SubscribeReturnCodes::Failure],
+            }));
+        let mut client = scripted_client(ClientOptions::new(), &script).unwrap();
+
+        let token = client.subscribe(topics).unwrap();
+        let results = client.wait(&token, Some(Duration::from_millis(100))).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].granted, Some(QoS::AtLeastOnce));
+        assert!(results[0].is_downgraded());
+        assert_eq!(results[1].topic, "secret/#");
+        assert!(!results[1].is_granted());
+        assert!(client.subscriptions.contains_key("a/+"));
+        assert!(!client.subscriptions.contains_key("secret/#"));
+    }
+
+    #[test]
+    fn client_suback_mismatch_test() {
+        let mock_data = vec![0b00100000, 0x02, 0x00, 0x00, // CONNACK
+                             0b10010000, 0x03, 0x00, 0x01, 0x01]; // SUBACK 1, one code
+        let options = ClientOptions::new();
+        let connector = MockConnector::with_read_data(mock_data);
+        let mut client = options.connect_with(connector, &host("localhost")).unwrap();
+
+        let token = client.subscribe(vec![
+            SubscribeTopic { topic_path: "a/+".to_string(), qos: QoS::AtLeastOnce },
+            SubscribeTopic { topic_path: "b/+".to_string(), qos: QoS::AtLeastOnce },
+        ]).unwrap();
+        let clone = token.clone();
+        match client.accept() {
+            Err(Error::ProtocolViolation) => (),
+            result => panic!("expected ProtocolViolation, got {:?}", result),
+        }
+        assert!(clone.is_failed());
+        match client.wait(&token, Some(Duration::from_millis(100))) {
+            Err(Error::ProtocolViolation) => (),
+            result => panic!("expected ProtocolViolation, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn client_publish_confirmed_test() {
+        let confirmed = message("a/b", "confirmed", QoS::ExactlyOnce);
+        let meanwhile = message("a/b", "meanwhile", QoS::AtMostOnce);
+        let forgotten = message("a/b", "fire and forget", QoS::AtMostOnce);
+        let script = broker()
+            .expect(publish(&confirmed, Some(1), false))
+            .send(Packet::Pubrec(PacketIdentifier(1)))
+            .send(publish(&meanwhile, None, false))
+            .expect(Packet::Pubrel(PacketIdentifier(1)))
+            .send(Packet::Pubcomp(PacketIdentifier(1)))
+            .expect(publish(&forgotten, None, false));
+        let mut client = scripted_client(ClientOptions::new(), &script).unwrap();
+
+        client.publish_confirmed("a/b", "confirmed", PubOpt::exactly_once(), Duration::from_millis(100))
+            .unwrap();
+        assert!(client.outgoing.is_empty());
+        assert!(client.delivery.is_empty());
+        // the message received while waiting is not lost
+        assert_eq!(client.await().unwrap(), Some(meanwhile));
+
+        let token = client.publish_token("a/b", "fire and forget", PubOpt::at_most_once()).unwrap();
+        assert_eq!(token.pid(), None);
+        assert_eq!(token.result(), Some(()));
+        script.assert_done();
+    }
+
+    fn is_mqisdp_connect(packet: &Packet) -> bool {
+        match *packet {
+            Packet::Connect(ref connect) => connect.protocol == Protocol::MQIsdp(3),
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn client_protocol_fallback_test() {
+        let refused = Packet::Connack(mqtt3::Connack {
+            session_present: false,
+            code: ConnectReturnCode::RefusedProtocolVersion,
+        });
+
+        let script = ScriptedConnector::new()
+            .expect_with("CONNECT", is_connect)
+            .send(refused.clone());
+        match scripted_client(ClientOptions::new(), &script) {
+            Err(Error::ConnectionRefused(_)) => (),
+            Err(err) => panic!("unexpected error {:?}", err),
+            Ok(_) => panic!("connected without fallback"),
+        }
+        script.assert_done();
+
+        let script = ScriptedConnector::new()
+            .expect_with("CONNECT", is_connect)
+            .send(refused)
+            .expect_with("MQIsdp CONNECT", is_mqisdp_connect)
+            .send(connack(false));
+        let mut options = ClientOptions::new();
+        options.set_protocol_fallback(true);
+        let client = scripted_client(options, &script).unwrap();
+        assert_eq!(client.protocol(), Protocol::MQIsdp(3));
+        script.assert_done();
+    }
+
+    #[test]
+    fn client_mqisdp_client_id_test() {
+        let mock_data = vec![0b00100000, 0x02, 0x00, 0x00];
+        let host_port = host("localhost");
+        let mut options = ClientOptions::new();
+        options.set_protocol(Protocol::MQIsdp(3))
+            .set_client_id("a_client_id_longer_than_23".to_string());
+        match options.connect_with(MockConnector::with_read_data(mock_data.clone()), &host_port) {
+            Err(Error::InvalidClientId) => (),
+            _ => panic!("long client id accepted"),
+        }
+
+        let mut options = ClientOptions::new();
+        options.set_protocol(Protocol::MQIsdp(3)).set_clean_session(false);
+        let client = options.connect_with(MockConnector::with_read_data(mock_data), &host_port)
+            .unwrap();
+        assert!(client.session_present());
+    }
+
+    #[test]
+    fn client_scripted_test() {
+        let message = Message {
+            topic: "a/b".to_topic_name().unwrap(),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            pid: None,
+            payload: Arc::new(b"hello".to_vec()),
+        };
+        let topic = SubscribeTopic { topic_path: "secret/#".to_string(), qos: QoS::AtLeastOnce };
+        let script = broker()
+            .expect(Packet::Subscribe(mqtt3::Subscribe {
+                pid: PacketIdentifier(1),
+                topics: vec![topic.clone()],
+            }))
+            .send(Packet::Suback(mqtt3::Suback {
+                pid: PacketIdentifier(1),
+                return_codes: vec![SubscribeReturnCodes::Failure],
+            }))
+            .disconnect()
+            .refuse_connect()
+            .expect_with("CONNECT", is_connect)
+            .send(connack(false))
+            .expect(publish(&message, None, false));
+
+        let mut options = ClientOptions::new();
+        let backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(1))
+            .with_max_attempts(2);
+        options.set_reconnect(ReconnectMethod::Backoff(backoff));
+        let mut client = scripted_client(options, &script).unwrap();
+
+        let token = client.subscribe(vec![topic]).unwrap();
+        let results = client.wait(&token, Some(Duration::from_millis(100))).unwrap();
+        assert!(!results[0].is_granted());
+
+        // the broker drops us, the first reconnect is refused
+        assert_eq!(client.accept().unwrap(), None);
+        assert_eq!(script.connects(), 2);
+        client.publish("a/b", "hello", PubOpt::at_most_once()).unwrap();
+        script.assert_done();
+    }
+
+    #[test]
+    fn client_reconnect_after_test() {
+        let script = broker().disconnect().refuse_connect();
+
+        let mut options = ClientOptions::new();
+        options.set_reconnect(ReconnectMethod::ReconnectAfter(Duration::from_millis(1)));
+        let mut client = scripted_client(options, &script).unwrap();
+
+        // a single attempt, the refusal is returned instead of retrying
+        assert!(client.accept().is_err());
+        assert_eq!(client.state, ClientState::Disconnected);
+        script.assert_done();
+    }
+
+    #[test]
+    fn client_fault_reconnect_test() {
+        let payload = String::from_utf8(vec![b'x'; 32]).unwrap();
+        let script = broker()
+            .send(publish(&message("a/b", &payload, QoS::AtMostOnce), None, false))
+            .expect_with("CONNECT", is_connect)
+            .send(connack(false));
+        // the connection resets halfway through the PUBLISH
+        let connector = FaultConnector::new(script.clone(), 7)
+            .drop_after(40)
+            .delay(0.5, Duration::from_millis(1));
+
+        let mut options = ClientOptions::new();
+        options.set_client_id("fault".to_string());
+        options.set_reconnect(ReconnectMethod::ReconnectAfter(Duration::from_millis(1)));
+        let mut client = options.connect_with(connector, &host("localhost")).unwrap();
+
+        assert_eq!(client.accept().unwrap(), None);
+        assert_eq!(client.state, ClientState::Connected);
+        assert_eq!(client.metrics().reconnects, 1);
+        script.assert_done();
+    }
+
+    #[test]
+    fn client_failover_refused_test() {
+        let refused = Packet::Connack(mqtt3::Connack {
+            session_present: false,
+            code: ConnectReturnCode::ServerUnavailable,
+        });
+        let script = ScriptedConnector::new()
+            .expect_with("CONNECT", is_connect)
+            .send(refused.clone())
+            .expect_with("CONNECT", is_connect)
+            .send(connack(false))
+            .disconnect()
+            .expect_with("CONNECT", is_connect)
+            .send(refused)
+            .expect_with("CONNECT", is_connect)
+            .send(connack(false));
+
+        let mut options = ClientOptions::new();
+        options.set_reconnect(ReconnectMethod::ReconnectAfter(Duration::from_millis(1)));
+        let hosts = vec![host("a"), host("b")];
+        // the first broker accepts TCP but refuses CONNECT
+        let mut client = options.connect_with_endpoints(script.clone(), hosts).unwrap();
+        assert_eq!(client.active_endpoint().to_string(), "b:1883");
+
+        // priority-first reconnects try the primary again, then fail over
+        assert_eq!(client.accept().unwrap(), None);
+        assert_eq!(client.active_endpoint().to_string(), "b:1883");
+        assert_eq!(script.connects(), 4);
+        script.assert_done();
     }
 }
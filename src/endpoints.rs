@@ -0,0 +1,113 @@
+use url::Url;
+use netopt::HostAndPort;
+use error::Result;
+use FailoverPolicy;
+
+/// Brokers a client may connect to, in priority order.
+pub struct Endpoints {
+    hosts: Vec<HostAndPort>,
+    active: usize,
+    policy: FailoverPolicy,
+}
+
+impl Endpoints {
+    pub fn new(hosts: Vec<HostAndPort>, policy: FailoverPolicy) -> Endpoints {
+        assert!(!hosts.is_empty());
+        Endpoints {
+            hosts: hosts,
+            active: 0,
+            policy: policy,
+        }
+    }
+
+    pub fn get(&self, idx: usize) -> &HostAndPort {
+        &self.hosts[idx]
+    }
+
+    pub fn active(&self) -> &HostAndPort {
+        &self.hosts[self.active]
+    }
+
+    pub fn set_active(&mut self, idx: usize) {
+        self.active = idx;
+    }
+
+    /// Order in which the endpoints are tried by the initial connect.
+    pub fn connect_order(&self) -> Vec<usize> {
+        self.order(0)
+    }
+
+    /// Order in which the endpoints are tried by a reconnect: round-robin
+    /// starts with the endpoint after the active one, priority-first always
+    /// starts over with the first endpoint.
+    pub fn reconnect_order(&self) -> Vec<usize> {
+        match self.policy {
+            FailoverPolicy::RoundRobin => self.order(self.active + 1),
+            FailoverPolicy::PriorityFirst => self.order(0),
+        }
+    }
+
+    fn order(&self, first: usize) -> Vec<usize> {
+        let len = self.hosts.len();
+        (0..len).map(|i| (first + i) % len).collect()
+    }
+}
+
+/// Splits a `mqtt://a:1883,b:1883` style string into one URL per host.
+/// The path and query following the last host, as in `ws://a,b/mqtt`, are
+/// shared by every host.
+pub fn parse_urls(urls: &str) -> Result<Vec<Url>> {
+    let sep = match urls.find("://") {
+        Some(idx) => idx + 3,
+        None => return Ok(vec![try!(Url::parse(urls))]),
+    };
+    let (scheme, rest) = urls.split_at(sep);
+    let end = rest.find(|c| c == '/' || c == '?' || c == '#').unwrap_or(rest.len());
+    let (hosts, suffix) = rest.split_at(end);
+    let mut parsed = Vec::new();
+    for host in hosts.split(',') {
+        parsed.push(try!(Url::parse(&format!("{}{}{}", scheme, host.trim(), suffix))));
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Endpoints, parse_urls};
+    use url::{Host, HostAndPort};
+    use FailoverPolicy;
+
+    fn hosts() -> Vec<HostAndPort> {
+        ["a", "b", "c"]
+            .iter()
+            .map(|host| HostAndPort { host: Host::Domain(host.to_string()), port: 1883 })
+            .collect()
+    }
+
+    #[test]
+    fn endpoints_order_test() {
+        let mut endpoints = Endpoints::new(hosts(), FailoverPolicy::RoundRobin);
+        assert_eq!(endpoints.connect_order(), vec![0, 1, 2]);
+        endpoints.set_active(1);
+        assert_eq!(endpoints.reconnect_order(), vec![2, 0, 1]);
+        assert_eq!(endpoints.active().to_string(), "b:1883");
+
+        let mut endpoints = Endpoints::new(hosts(), FailoverPolicy::PriorityFirst);
+        endpoints.set_active(2);
+        assert_eq!(endpoints.reconnect_order(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn parse_urls_test() {
+        let urls = parse_urls("mqtts://a:8883, b:1234,c").unwrap();
+        let urls: Vec<String> = urls.iter().map(|url| url.to_string()).collect();
+        assert_eq!(urls, vec!["mqtts://a:8883", "mqtts://b:1234", "mqtts://c"]);
+
+        let urls = parse_urls("ws://a, b:8080/mqtt?id=1").unwrap();
+        let urls: Vec<String> = urls.iter().map(|url| url.to_string()).collect();
+        assert_eq!(urls, vec!["ws://a/mqtt?id=1", "ws://b:8080/mqtt?id=1"]);
+
+        assert_eq!(parse_urls("mqtt://localhost").unwrap().len(), 1);
+        assert!(parse_urls("localhost").is_err());
+    }
+}
@@ -20,7 +20,14 @@ pub enum Error {
     ProtocolViolation,
     Disconnected,
     Timeout,
+    WouldBlock,
+    QueueFull,
+    Vetoed,
+    NoEndpoint,
+    TlsUnavailable,
+    InvalidClientId,
     InvalidUrlScheme(url::Url),
+    UrlParse(url::ParseError),
     UnhandledPuback(PacketIdentifier),
     UnhandledPubrec(PacketIdentifier),
     UnhandledPubrel(PacketIdentifier),
@@ -38,6 +45,12 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<url::ParseError> for Error {
+    fn from(err: url::ParseError) -> Error {
+        Error::UrlParse(err)
+    }
+}
+
 impl From<MqttError> for Error {
     fn from(err: MqttError) -> Error {
         match err {
@@ -72,6 +85,7 @@ impl fmt::Display for Error {
             Error::UnhandledPubrec(PacketIdentifier(pi)) => fmt::write(f, format_args!("{:?}", pi)),
             Error::UnhandledPubrel(PacketIdentifier(pi)) => fmt::write(f, format_args!("{:?}", pi)),
             Error::UnhandledPubcomp(PacketIdentifier(pi)) => fmt::write(f, format_args!("{:?}", pi)),
+            Error::UrlParse(ref err) => write!(f, "Invalid url: {}", err),
             Error::ConnectionRefused(crc) => fmt::write(f, format_args!("{:?}", crc)),
             Error::Storage(ref err) => write!(f, "Storage error: {:?}", err),
             Error::Mqtt(ref err) => write!(f, "MQTT error: {:?}", err),
@@ -96,7 +110,14 @@ impl error::Error for Error {
             Error::ProtocolViolation => "ProtocolViolation",
             Error::Disconnected => "Disconnected",
             Error::Timeout => "Timeout",
+            Error::WouldBlock => "WouldBlock",
+            Error::QueueFull => "QueueFull",
+            Error::Vetoed => "Publish vetoed by an interceptor",
+            Error::NoEndpoint => "No broker endpoint to connect to",
+            Error::TlsUnavailable => "Built without TLS support, enable the ssl or rustls feature",
+            Error::InvalidClientId => "Client identifier not allowed by the protocol",
             Error::InvalidUrlScheme(_) => "Invalid scheme specified in url",
+            Error::UrlParse(ref err) => err.description(),
             Error::UnhandledPuback(_) => "UnhandledPuback",
             Error::UnhandledPubrec(_) => "UnhandledPubrec",
             Error::UnhandledPubrel(_) => "UnhandledPubrel",
@@ -115,6 +136,7 @@ impl error::Error for Error {
             // types (either `&io::Error` or `&num::ParseIntError`)
             // to a trait object `&Error`. This works because both error types
             // implement `Error`.
+            Error::UrlParse(ref err) => Some(err),
             Error::Storage(ref err) => Some(err),
             Error::Mqtt(ref err) => Some(err),
             Error::Netopt(ref err) => Some(err),
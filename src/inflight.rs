@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+use mqtt3::{Message, PacketIdentifier};
+
+/// State of an outgoing QoS 1 or QoS 2 flow, named after the packet the
+/// client is waiting for.
+#[derive(Debug, Clone)]
+pub enum Outgoing {
+    /// QoS 1 PUBLISH was sent
+    Puback(Message),
+    /// QoS 2 PUBLISH was sent
+    Pubrec(Message),
+    /// QoS 2 PUBREL was sent
+    Pubcomp,
+}
+
+/// State of an incomming QoS 2 flow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Incomming {
+    /// PUBREC was sent, the message is kept in the incomming store
+    Pubrel,
+    /// The message was released to the user, PUBCOMP is sent on `complete`
+    Pubcomp,
+    /// The message was released to the user of a previous process, PUBCOMP
+    /// is sent on the next PUBREL
+    Delivered,
+}
+
+/// Packets waiting for an acknowledgement, keyed by packet identifier.
+///
+/// Acknowledgements are matched by identifier so they may arrive in any
+/// order, while `ordered` still yields the entries in insertion order.
+#[derive(Debug)]
+pub struct InFlight<T> {
+    seq: u64,
+    entries: BTreeMap<PacketIdentifier, (u64, T)>,
+}
+
+impl<T> InFlight<T> {
+    pub fn new() -> InFlight<T> {
+        InFlight {
+            seq: 0,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Inserts a new entry at the end of the flight order.
+    pub fn insert(&mut self, pid: PacketIdentifier, value: T) -> Option<T> {
+        self.seq += 1;
+        self.entries.insert(pid, (self.seq, value)).map(|(_, old)| old)
+    }
+
+    /// Moves an existing entry to its next state, keeping its position.
+    pub fn update(&mut self, pid: PacketIdentifier, value: T) -> Option<T> {
+        let seq = match self.entries.get(&pid) {
+            Some(&(seq, _)) => seq,
+            None => return self.insert(pid, value),
+        };
+        self.entries.insert(pid, (seq, value)).map(|(_, old)| old)
+    }
+
+    pub fn get(&self, pid: PacketIdentifier) -> Option<&T> {
+        self.entries.get(&pid).map(|&(_, ref value)| value)
+    }
+
+    pub fn remove(&mut self, pid: PacketIdentifier) -> Option<T> {
+        self.entries.remove(&pid).map(|(_, value)| value)
+    }
+
+    pub fn contains(&self, pid: PacketIdentifier) -> bool {
+        self.entries.contains_key(&pid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Entries in the order they were first inserted.
+    pub fn ordered(&self) -> Vec<(PacketIdentifier, &T)> {
+        let mut entries: Vec<(u64, PacketIdentifier, &T)> = self.entries
+            .iter()
+            .map(|(pid, &(seq, ref value))| (seq, *pid, value))
+            .collect();
+        entries.sort_by_key(|&(seq, _, _)| seq);
+        entries.into_iter().map(|(_, pid, value)| (pid, value)).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::InFlight;
+    use mqtt3::PacketIdentifier;
+
+    #[test]
+    fn inflight_order_test() {
+        let mut inflight = InFlight::new();
+        inflight.insert(PacketIdentifier(65535), "a");
+        inflight.insert(PacketIdentifier(1), "b");
+        inflight.insert(PacketIdentifier(2), "c");
+        inflight.update(PacketIdentifier(65535), "A");
+        assert_eq!(inflight.remove(PacketIdentifier(1)), Some("b"));
+        assert_eq!(inflight.remove(PacketIdentifier(1)), None);
+
+        let ordered = inflight.ordered();
+        assert_eq!(ordered, vec![(PacketIdentifier(65535), &"A"), (PacketIdentifier(2), &"c")]);
+    }
+}
@@ -0,0 +1,62 @@
+use mqtt3::{Packet, Publish};
+
+/// What happens to a PUBLISH after an `Interceptor` has seen it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Hand the packet, possibly changed, to the next interceptor
+    Pass,
+    /// Drop the packet as if it was never sent or received
+    Veto,
+}
+
+/// Hooks run by the client on every packet it sends or receives.
+///
+/// Interceptors run in the order they were added to `ClientOptions`, and a
+/// veto skips the remaining ones. Outgoing packets are intercepted before
+/// they are written, so a rewritten PUBLISH is also what gets
+/// retransmitted, while the stores keep the message as published.
+///
+/// Only PUBLISH packets can be vetoed, the client keeps state for the
+/// others. A vetoed outgoing PUBLISH fails with `Error::Vetoed` before a
+/// packet identifier is allocated, and a vetoed retransmission gives up its
+/// flow. A vetoed incomming PUBLISH is still acknowledged, but never
+/// delivered. Vetoed packets are not counted in `Metrics`, in either
+/// direction.
+pub trait Interceptor: Send {
+    fn outgoing_publish(&mut self, _publish: &mut Publish) -> Verdict {
+        Verdict::Pass
+    }
+
+    fn incomming_publish(&mut self, _publish: &mut Publish) -> Verdict {
+        Verdict::Pass
+    }
+
+    /// Sees every packet but PUBLISH before it is written.
+    fn outgoing(&mut self, _packet: &mut Packet) {}
+
+    /// Sees every packet but PUBLISH once it was read.
+    fn incomming(&mut self, _packet: &mut Packet) {}
+}
+
+/// Runs `packet` through `interceptors`, using `publish` or `other` to pick
+/// the direction.
+pub fn intercept<P, F>(interceptors: &mut [Box<Interceptor>],
+                       packet: &mut Packet,
+                       publish: P,
+                       other: F)
+                       -> Verdict
+    where P: Fn(&mut Interceptor, &mut Publish) -> Verdict,
+          F: Fn(&mut Interceptor, &mut Packet)
+{
+    for interceptor in interceptors.iter_mut() {
+        if let Packet::Publish(ref mut p) = *packet {
+            if publish(&mut **interceptor, p) == Verdict::Veto {
+                trace!("Vetoed {:?}", p);
+                return Verdict::Veto;
+            }
+            continue;
+        }
+        other(&mut **interceptor, packet);
+    }
+    Verdict::Pass
+}
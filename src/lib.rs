@@ -3,12 +3,28 @@ extern crate rand;
 extern crate byteorder;
 extern crate mqtt3;
 extern crate url;
+extern crate sha1;
+extern crate base64;
 #[cfg(feature = "ssl")]
 extern crate openssl;
+#[cfg(feature = "rustls")]
+extern crate tls_rustls as rustls;
+#[cfg(feature = "rustls")]
+extern crate webpki;
+#[cfg(feature = "rustls")]
+extern crate webpki_roots;
 
 mod error;
 mod sub;
+mod inflight;
+mod backoff;
+mod endpoints;
+mod router;
+mod token;
 mod client;
+mod broker;
+mod metrics;
+mod interceptor;
 pub mod store;
 pub mod netopt;
 
@@ -19,7 +35,20 @@ pub use error::{
 
 pub use sub::{
     ToSubTopics,
-    ToUnSubTopics
+    ToUnSubTopics,
+    SubscribeResult
+};
+
+pub use token::Token;
+
+pub use backoff::{
+    Backoff,
+    Jitter
+};
+
+pub use router::{
+    RouteId,
+    topic_matches
 };
 
 pub use client::{
@@ -27,19 +56,50 @@ pub use client::{
     ClientOptions
 };
 
+pub use broker::Broker;
+
+pub use interceptor::{
+    Interceptor,
+    Verdict
+};
+
+pub use metrics::{
+    Metrics,
+    PacketType,
+    Traffic
+};
+
 use std::sync::Arc;
 use std::ops;
 use std::time::Duration;
 pub use mqtt3::{QoS, ToTopicPath, TopicPath, SubscribeTopic, Topic, Message, PacketIdentifier};
-pub use store::{Store, MemoryStorage};
+pub use store::{Store, MemoryStorage, FileStorage, SyncPolicy};
 
 const MAX_QOS: QoS = mqtt3::QoS::AtLeastOnce;
 
 pub trait PubSub {
     fn publish<T: ToTopicPath, P: ToPayload>(&mut self, topic: T, payload: P, pubopt: PubOpt) -> Result<()>;
-    fn subscribe<S: ToSubTopics>(&mut self, subs: S) -> Result<()>;
+    fn subscribe<S: ToSubTopics>(&mut self, subs: S) -> Result<SubscribeToken>;
     fn unsubscribe<U: ToUnSubTopics>(&mut self, unsubs: U) -> Result<()>;
-    fn disconnect(self) -> Result<()>;
+    /// Sends DISCONNECT and closes the connection. With a `timeout` the
+    /// client first waits up to that long for in-flight QoS 1 and QoS 2
+    /// publishes to complete.
+    fn disconnect(self, timeout: Option<Duration>) -> Result<Undelivered>;
+}
+
+/// Outgoing flows that had not completed when the client disconnected.
+#[derive(Debug, Clone, Default)]
+pub struct Undelivered {
+    /// Publishes the broker never acknowledged
+    pub messages: Vec<Message>,
+    /// QoS 2 publishes the broker received but never completed with PUBCOMP
+    pub unreleased: Vec<PacketIdentifier>,
+}
+
+impl Undelivered {
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty() && self.unreleased.is_empty()
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -52,7 +112,27 @@ pub enum ClientState {
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ReconnectMethod {
     ForeverDisconnect,
-    ReconnectAfter(Duration)
+    /// Waits, then makes a single reconnect attempt
+    ReconnectAfter(Duration),
+    Backoff(Backoff),
+}
+
+/// Which broker a reconnect tries first when several are configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailoverPolicy {
+    /// Continue with the broker after the one that was lost
+    RoundRobin,
+    /// Always start over with the first broker in the list
+    PriorityFirst,
+}
+
+/// What a full offline queue does with another publish.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    DropOldest,
+    DropNewest,
+    /// Fail the publish with `Error::QueueFull`
+    Error,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -153,6 +233,12 @@ impl ops::Not for PubOpt {
     }
 }
 
+/// Completes with the per topic outcome once SUBACK arrives.
+pub type SubscribeToken = Token<Vec<SubscribeResult>>;
+
+/// Completes once the broker has acknowledged a publish.
+pub type DeliveryToken = Token<()>;
+
 pub type Payload = Arc<Vec<u8>>;
 
 pub trait ToPayload {
@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::{self, Read};
+use std::time::Duration;
+use mqtt3::{Packet, QoS};
+
+/// Control packet types, as counted by `Metrics`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PacketType {
+    Connect,
+    Connack,
+    Publish,
+    Puback,
+    Pubrec,
+    Pubrel,
+    Pubcomp,
+    Subscribe,
+    Suback,
+    Unsubscribe,
+    Unsuback,
+    Pingreq,
+    Pingresp,
+    Disconnect,
+}
+
+impl PacketType {
+    pub fn of(packet: &Packet) -> PacketType {
+        match *packet {
+            Packet::Connect(_) => PacketType::Connect,
+            Packet::Connack(_) => PacketType::Connack,
+            Packet::Publish(_) => PacketType::Publish,
+            Packet::Puback(_) => PacketType::Puback,
+            Packet::Pubrec(_) => PacketType::Pubrec,
+            Packet::Pubrel(_) => PacketType::Pubrel,
+            Packet::Pubcomp(_) => PacketType::Pubcomp,
+            Packet::Subscribe(_) => PacketType::Subscribe,
+            Packet::Suback(_) => PacketType::Suback,
+            Packet::Unsubscribe(_) => PacketType::Unsubscribe,
+            Packet::Unsuback(_) => PacketType::Unsuback,
+            Packet::Pingreq => PacketType::Pingreq,
+            Packet::Pingresp => PacketType::Pingresp,
+            Packet::Disconnect => PacketType::Disconnect,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PacketType::Connect => "connect",
+            PacketType::Connack => "connack",
+            PacketType::Publish => "publish",
+            PacketType::Puback => "puback",
+            PacketType::Pubrec => "pubrec",
+            PacketType::Pubrel => "pubrel",
+            PacketType::Pubcomp => "pubcomp",
+            PacketType::Subscribe => "subscribe",
+            PacketType::Suback => "suback",
+            PacketType::Unsubscribe => "unsubscribe",
+            PacketType::Unsuback => "unsuback",
+            PacketType::Pingreq => "pingreq",
+            PacketType::Pingresp => "pingresp",
+            PacketType::Disconnect => "disconnect",
+        }
+    }
+}
+
+/// Packets and bytes of one packet type.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Traffic {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// A snapshot of what a `Client` has been doing, from `Client::metrics`.
+///
+/// Counters add up over the life of the client, across reconnects. Packets
+/// vetoed by an `Interceptor` are not counted, whichever their direction.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    pub sent: BTreeMap<PacketType, Traffic>,
+    pub received: BTreeMap<PacketType, Traffic>,
+    /// Messages published, by QoS, not counting retransmissions
+    pub publishes_sent: [u64; 3],
+    /// Messages received, by QoS, including redeliveries
+    pub publishes_received: [u64; 3],
+    pub reconnect_attempts: u64,
+    pub reconnects: u64,
+    /// QoS 1 and QoS 2 publishes waiting for the broker
+    pub outgoing: usize,
+    /// QoS 2 messages received and not completed yet
+    pub incomming: usize,
+    /// SUBSCRIBE and UNSUBSCRIBE waiting for the broker
+    pub subscribes: usize,
+    pub unsubscribes: usize,
+    /// Publishes queued while disconnected
+    pub offline: usize,
+    /// Round trip of the last answered PINGREQ
+    pub ping_rtt: Option<Duration>,
+    pub since_last_flush: Duration,
+}
+
+impl Metrics {
+    /// Renders the snapshot in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        for &(name, traffic) in &[("sent", &self.sent), ("received", &self.received)] {
+            header(&mut out, &format!("mqttc_packets_{}_total", name), "counter",
+                   &format!("MQTT packets {} by type.", name));
+            for (kind, traffic) in traffic {
+                let _ = writeln!(out, "mqttc_packets_{}_total{{type=\"{}\"}} {}",
+                                 name, kind.name(), traffic.packets);
+            }
+            header(&mut out, &format!("mqttc_bytes_{}_total", name), "counter",
+                   &format!("Bytes of MQTT packets {} by type.", name));
+            for (kind, traffic) in traffic {
+                let _ = writeln!(out, "mqttc_bytes_{}_total{{type=\"{}\"}} {}",
+                                 name, kind.name(), traffic.bytes);
+            }
+        }
+        for &(name, publishes) in &[("sent", &self.publishes_sent),
+                                    ("received", &self.publishes_received)] {
+            header(&mut out, &format!("mqttc_publishes_{}_total", name), "counter",
+                   &format!("Messages {} by QoS.", name));
+            for (qos, count) in publishes.iter().enumerate() {
+                let _ = writeln!(out, "mqttc_publishes_{}_total{{qos=\"{}\"}} {}",
+                                 name, qos, count);
+            }
+        }
+        header(&mut out, "mqttc_reconnect_attempts_total", "counter", "Reconnect attempts.");
+        let _ = writeln!(out, "mqttc_reconnect_attempts_total {}", self.reconnect_attempts);
+        header(&mut out, "mqttc_reconnects_total", "counter", "Successful reconnects.");
+        let _ = writeln!(out, "mqttc_reconnects_total {}", self.reconnects);
+        header(&mut out, "mqttc_inflight", "gauge", "Flows waiting for the broker by queue.");
+        for &(queue, depth) in &[("outgoing", self.outgoing),
+                                 ("incomming", self.incomming),
+                                 ("subscribe", self.subscribes),
+                                 ("unsubscribe", self.unsubscribes),
+                                 ("offline", self.offline)] {
+            let _ = writeln!(out, "mqttc_inflight{{queue=\"{}\"}} {}", queue, depth);
+        }
+        if let Some(rtt) = self.ping_rtt {
+            header(&mut out, "mqttc_ping_rtt_seconds", "gauge", "Round trip of the last ping.");
+            let _ = writeln!(out, "mqttc_ping_rtt_seconds {}", seconds(rtt));
+        }
+        header(&mut out, "mqttc_since_last_flush_seconds", "gauge",
+               "Time since data was last flushed to the broker.");
+        let _ = writeln!(out, "mqttc_since_last_flush_seconds {}", seconds(self.since_last_flush));
+        out
+    }
+}
+
+pub fn count_sent(metrics: &mut Metrics, packet: &Packet, bytes: usize) {
+    count(&mut metrics.sent, packet, bytes);
+}
+
+pub fn count_received(metrics: &mut Metrics, packet: &Packet, bytes: usize) {
+    count(&mut metrics.received, packet, bytes);
+    if let Packet::Publish(ref publish) = *packet {
+        metrics.publishes_received[publish.qos.to_u8() as usize] += 1;
+    }
+}
+
+pub fn count_published(metrics: &mut Metrics, qos: QoS) {
+    metrics.publishes_sent[qos.to_u8() as usize] += 1;
+}
+
+fn count(traffic: &mut BTreeMap<PacketType, Traffic>, packet: &Packet, bytes: usize) {
+    let traffic = traffic.entry(PacketType::of(packet)).or_insert_with(Traffic::default);
+    traffic.packets += 1;
+    traffic.bytes += bytes as u64;
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+/// Counts the bytes read through it, to size incomming packets.
+pub struct CountingReader<'a, R: 'a> {
+    inner: &'a mut R,
+    count: usize,
+}
+
+impl<'a, R: Read> CountingReader<'a, R> {
+    pub fn new(inner: &'a mut R) -> CountingReader<'a, R> {
+        CountingReader {
+            inner: inner,
+            count: 0,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+impl<'a, R: Read> Read for CountingReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        self.count += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use mqtt3::{Packet, PacketIdentifier, QoS};
+    use super::{Metrics, PacketType, Traffic, count_sent, count_received, count_published};
+
+    #[test]
+    fn metrics_prometheus_test() {
+        let mut metrics = Metrics::default();
+        count_sent(&mut metrics, &Packet::Pingreq, 2);
+        count_sent(&mut metrics, &Packet::Pingreq, 2);
+        count_received(&mut metrics, &Packet::Puback(PacketIdentifier(1)), 4);
+        count_published(&mut metrics, QoS::AtLeastOnce);
+        metrics.ping_rtt = Some(Duration::from_millis(250));
+        assert_eq!(metrics.sent[&PacketType::Pingreq], Traffic { packets: 2, bytes: 4 });
+
+        let text = metrics.to_prometheus();
+        assert!(text.contains("# TYPE mqttc_packets_sent_total counter\n"));
+        assert!(text.contains("mqttc_packets_sent_total{type=\"pingreq\"} 2\n"));
+        assert!(text.contains("mqttc_bytes_received_total{type=\"puback\"} 4\n"));
+        assert!(text.contains("mqttc_publishes_sent_total{qos=\"1\"} 1\n"));
+        assert!(text.contains("mqttc_inflight{queue=\"outgoing\"} 0\n"));
+        assert!(text.contains("mqttc_ping_rtt_seconds 0.25\n"));
+    }
+}
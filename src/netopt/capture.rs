@@ -0,0 +1,483 @@
+use super::{NetworkStream, NetworkConnector, Result, Error, packet_len};
+use ::url::HostAndPort;
+use std::{cmp, str};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{self, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use mqtt3::MqttRead;
+
+struct Log {
+    out: Box<Write + Send>,
+    started: Instant,
+    connections: usize,
+}
+
+impl Log {
+    fn line(&mut self, connection: usize, tag: &str, text: &str) {
+        let elapsed = self.started.elapsed();
+        let _ = writeln!(self.out,
+                         "{}.{:06} {} {} {}",
+                         elapsed.as_secs(),
+                         elapsed.subsec_nanos() / 1000,
+                         connection,
+                         tag,
+                         text);
+        let _ = self.out.flush();
+    }
+}
+
+/// A capture log shared by the streams of a `CaptureConnector`.
+///
+/// Each line holds the seconds since the capture started, the connection
+/// number and then one of:
+///
+/// * `connect <host:port>`
+/// * `> <hex>` for bytes sent and `< <hex>` for bytes received
+/// * `> packet <packet>` and `< packet <packet>` for the decoded packets
+/// * `error <error>` for failed reads and writes, other than timeouts
+/// * `close`
+#[derive(Clone)]
+pub struct Capture(Arc<Mutex<Log>>);
+
+impl Capture {
+    pub fn new<W: Write + Send + 'static>(out: W) -> Capture {
+        let mut out = Box::new(out);
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs());
+        let started = started.unwrap_or(0);
+        let _ = writeln!(out, "# mqttc capture started at {}", started);
+        Capture(Arc::new(Mutex::new(Log {
+            out: out,
+            started: Instant::now(),
+            connections: 0,
+        })))
+    }
+
+    /// Captures to a new file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Capture> {
+        Ok(Capture::new(try!(File::create(path))))
+    }
+
+    /// Records `stream` as a new connection to `host_port`.
+    pub fn stream<S: NetworkStream>(&self, stream: S, host_port: &HostAndPort) -> CaptureStream<S> {
+        let connection = {
+            let mut log = self.0.lock().unwrap();
+            log.connections += 1;
+            let connection = log.connections;
+            log.line(connection, "connect", &host_port.to_string());
+            connection
+        };
+        CaptureStream {
+            inner: stream,
+            capture: self.clone(),
+            connection: connection,
+            sent: Vec::new(),
+            received: Vec::new(),
+        }
+    }
+
+    fn record(&self, connection: usize, direction: &str, data: &[u8], pending: &mut Vec<u8>) {
+        let mut log = self.0.lock().unwrap();
+        let mut hex = String::with_capacity(data.len() * 2);
+        for byte in data {
+            let _ = write!(hex, "{:02x}", byte);
+        }
+        log.line(connection, direction, &hex);
+
+        pending.extend_from_slice(data);
+        while let Some(len) = packet_len(pending) {
+            let packet: Vec<u8> = pending.drain(..len).collect();
+            let text = match io::Cursor::new(packet).read_packet() {
+                Ok(packet) => format!("packet {:?}", packet),
+                Err(err) => format!("packet undecodable: {:?}", err),
+            };
+            log.line(connection, direction, &text);
+        }
+    }
+
+    fn error(&self, connection: usize, err: &io::Error) {
+        match err.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => (),
+            _ => self.0.lock().unwrap().line(connection, "error", &err.to_string()),
+        }
+    }
+}
+
+/// A connector recording the traffic of another one to a `Capture`.
+#[derive(Clone)]
+pub struct CaptureConnector<C: NetworkConnector> {
+    base_connector: C,
+    capture: Capture,
+}
+
+impl<C: NetworkConnector> CaptureConnector<C> {
+    pub fn new(base_connector: C, capture: Capture) -> Self {
+        CaptureConnector {
+            base_connector: base_connector,
+            capture: capture,
+        }
+    }
+}
+
+impl<C> NetworkConnector for CaptureConnector<C>
+    where C: NetworkConnector + 'static
+{
+    type Stream = CaptureStream<C::Stream>;
+
+    fn connect(&self, host_port: &HostAndPort) -> Result<Self::Stream> {
+        let stream = try!(self.base_connector.connect(host_port));
+        Ok(self.capture.stream(stream, host_port))
+    }
+}
+
+/// A stream recording its traffic to a `Capture`.
+pub struct CaptureStream<S> {
+    inner: S,
+    capture: Capture,
+    connection: usize,
+    sent: Vec<u8>,
+    received: Vec<u8>,
+}
+
+impl<S> CaptureStream<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: Read> Read for CaptureStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(n) => {
+                if n > 0 {
+                    self.capture.record(self.connection, "<", &buf[..n], &mut self.received);
+                }
+                Ok(n)
+            }
+            Err(err) => {
+                self.capture.error(self.connection, &err);
+                Err(err)
+            }
+        }
+    }
+}
+
+impl<S: Write> Write for CaptureStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.inner.write(buf) {
+            Ok(n) => {
+                self.capture.record(self.connection, ">", &buf[..n], &mut self.sent);
+                Ok(n)
+            }
+            Err(err) => {
+                self.capture.error(self.connection, &err);
+                Err(err)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S> Drop for CaptureStream<S> {
+    fn drop(&mut self) {
+        self.capture.0.lock().unwrap().line(self.connection, "close", "");
+    }
+}
+
+impl<S: NetworkStream> NetworkStream for CaptureStream<S> {
+    #[inline]
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    #[inline]
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(dur)
+    }
+
+    #[inline]
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(dur)
+    }
+
+    #[inline]
+    fn shutdown(&mut self, how: net::Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+}
+
+/// Bytes received on a recorded connection, after the client had sent
+/// `after` packets.
+#[derive(Debug, Clone)]
+struct Chunk {
+    after: usize,
+    data: Vec<u8>,
+}
+
+/// A connector playing the broker side of a capture back.
+///
+/// Each connect replays the next recorded connection. The bytes the broker
+/// sent are handed to the client once it has sent as many packets as it
+/// had when they were received, and the connection ends with EOF. What
+/// the client sends is not compared to the capture and timing is not
+/// reproduced.
+#[derive(Debug, Clone)]
+pub struct ReplayConnector {
+    connections: Arc<Mutex<VecDeque<Vec<Chunk>>>>,
+}
+
+impl ReplayConnector {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ReplayConnector> {
+        ReplayConnector::from_reader(BufReader::new(try!(File::open(path))))
+    }
+
+    /// Parses a capture. The lines of a connection may be interleaved
+    /// with those of the next one, e.g. when the client reconnected before
+    /// the old stream was dropped.
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<ReplayConnector> {
+        let mut connections: Vec<Vec<Chunk>> = Vec::new();
+        // by connection number: index in `connections`, packets sent and
+        // bytes of the unfinished one
+        let mut open: HashMap<usize, (usize, usize, Vec<u8>)> = HashMap::new();
+        for line in reader.lines() {
+            let line = try!(line);
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 3 {
+                return Err(invalid(&line));
+            }
+            let connection: usize = try!(fields[1].parse().map_err(|_| invalid(&line)));
+            if fields[2] == "connect" {
+                open.insert(connection, (connections.len(), 0, Vec::new()));
+                connections.push(Vec::new());
+                continue;
+            }
+            let (index, sent, pending) = match open.get_mut(&connection) {
+                Some(&mut (index, ref mut sent, ref mut pending)) => (index, sent, pending),
+                None => return Err(invalid(&line)),
+            };
+            match (fields[2], fields.get(3)) {
+                (_, Some(&"packet")) => (),
+                (">", Some(hex)) => {
+                    pending.extend(try!(from_hex(hex).ok_or_else(|| invalid(&line))));
+                    while let Some(len) = packet_len(pending) {
+                        pending.drain(..len);
+                        *sent += 1;
+                    }
+                }
+                ("<", Some(hex)) => {
+                    let data = try!(from_hex(hex).ok_or_else(|| invalid(&line)));
+                    connections[index].push(Chunk {
+                        after: *sent,
+                        data: data,
+                    });
+                }
+                ("error", _) | ("close", _) => (),
+                _ => return Err(invalid(&line)),
+            }
+        }
+        Ok(ReplayConnector { connections: Arc::new(Mutex::new(connections.into_iter().collect())) })
+    }
+
+    /// Number of recorded connections not replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+}
+
+fn invalid(line: &str) -> Error {
+    io::Error::new(ErrorKind::InvalidData, format!("Invalid capture line: {}", line)).into()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| str::from_utf8(pair).ok().and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+impl NetworkConnector for ReplayConnector {
+    type Stream = ReplayStream;
+
+    fn connect(&self, host_port: &HostAndPort) -> Result<Self::Stream> {
+        let chunks = match self.connections.lock().unwrap().pop_front() {
+            Some(chunks) => chunks,
+            None => {
+                return Err(io::Error::new(ErrorKind::ConnectionRefused, "capture exhausted").into())
+            }
+        };
+        Ok(ReplayStream {
+            peer_addr: try!(host_port.to_socket_addrs()).next().unwrap(),
+            chunks: chunks.into_iter().collect(),
+            written: Vec::new(),
+            sent: 0,
+        })
+    }
+}
+
+pub struct ReplayStream {
+    peer_addr: SocketAddr,
+    chunks: VecDeque<Chunk>,
+    written: Vec<u8>,
+    sent: usize,
+}
+
+impl Write for ReplayStream {
+    fn write(&mut self, msg: &[u8]) -> io::Result<usize> {
+        self.written.extend_from_slice(msg);
+        while let Some(len) = packet_len(&self.written) {
+            self.written.drain(..len);
+            self.sent += 1;
+        }
+        Ok(msg.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for ReplayStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = match self.chunks.front_mut() {
+            Some(ref chunk) if chunk.after > self.sent => {
+                return Err(io::Error::new(ErrorKind::WouldBlock, "replay waits for the client"));
+            }
+            Some(chunk) => {
+                let len = cmp::min(buf.len(), chunk.data.len());
+                for (dst, src) in buf.iter_mut().zip(chunk.data.drain(..len)) {
+                    *dst = src;
+                }
+                len
+            }
+            None => return Ok(0),
+        };
+        if self.chunks.front().map_or(false, |chunk| chunk.data.is_empty()) {
+            self.chunks.pop_front();
+        }
+        Ok(len)
+    }
+}
+
+impl NetworkStream for ReplayStream {
+    #[inline]
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+
+    #[inline]
+    fn set_read_timeout(&self, _dur: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn set_write_timeout(&self, _dur: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn shutdown(&mut self, _how: net::Shutdown) -> io::Result<()> {
+        self.chunks.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, ErrorKind, Read, Write};
+    use std::sync::{Arc, Mutex};
+    use mqtt3::{MqttRead, MqttWrite, Packet, PacketIdentifier};
+    use super::{Capture, CaptureConnector, ReplayConnector};
+    use netopt::{NetworkConnector, HostAndPort};
+    use netopt::mock::MockConnector;
+
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn capture_replay_test() {
+        let mut broker_data = Vec::new();
+        broker_data.write_packet(&Packet::Pingresp).unwrap();
+        broker_data.write_packet(&Packet::Puback(PacketIdentifier(7))).unwrap();
+
+        let log = Shared(Arc::new(Mutex::new(Vec::new())));
+        let connector = CaptureConnector::new(MockConnector::with_read_data(broker_data),
+                                              Capture::new(log.clone()));
+        let host = HostAndPort::parse("127.0.0.1:1883").unwrap();
+        {
+            let mut stream = connector.connect(&host).unwrap();
+            stream.write_packet(&Packet::Pingreq).unwrap();
+            assert_eq!(stream.read_packet().unwrap(), Packet::Pingresp);
+            stream.write_packet(&Packet::Pingreq).unwrap();
+            assert_eq!(stream.read_packet().unwrap(), Packet::Puback(PacketIdentifier(7)));
+        }
+        let text = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        assert!(text.contains(" 1 connect 127.0.0.1:1883"));
+        assert!(text.contains(" 1 > c000"));
+        assert!(text.contains(" 1 < packet Pingresp"));
+        assert!(text.contains(" 1 close"));
+
+        let replay = ReplayConnector::from_reader(text.as_bytes()).unwrap();
+        let mut stream = replay.connect(&host).unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(stream.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+        stream.write_packet(&Packet::Pingreq).unwrap();
+        assert_eq!(stream.read_packet().unwrap(), Packet::Pingresp);
+        assert_eq!(stream.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+        stream.write_packet(&Packet::Pingreq).unwrap();
+        assert_eq!(stream.read_packet().unwrap(), Packet::Puback(PacketIdentifier(7)));
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert!(replay.connect(&host).is_err());
+    }
+
+    #[test]
+    fn replay_interleaved_test() {
+        // the client reconnected before the first stream was dropped
+        let text = "# mqttc capture started at 0\n\
+                    0.000010 1 connect 127.0.0.1:1883\n\
+                    0.000020 1 > c000\n\
+                    0.000030 1 < d000\n\
+                    0.000040 1 error connection reset\n\
+                    0.000050 2 connect 127.0.0.1:1883\n\
+                    0.000060 2 > c000\n\
+                    0.000070 1 close \n\
+                    0.000080 2 < 40020007\n\
+                    0.000090 2 close \n";
+        let replay = ReplayConnector::from_reader(text.as_bytes()).unwrap();
+        assert_eq!(replay.remaining(), 2);
+        let host = HostAndPort::parse("127.0.0.1:1883").unwrap();
+
+        let mut stream = replay.connect(&host).unwrap();
+        stream.write_packet(&Packet::Pingreq).unwrap();
+        assert_eq!(stream.read_packet().unwrap(), Packet::Pingresp);
+        let mut buf = [0; 8];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+        let mut stream = replay.connect(&host).unwrap();
+        stream.write_packet(&Packet::Pingreq).unwrap();
+        assert_eq!(stream.read_packet().unwrap(), Packet::Puback(PacketIdentifier(7)));
+        assert!(replay.connect(&host).is_err());
+    }
+}
@@ -11,6 +11,9 @@ pub enum Error {
     #[cfg(feature = "ssl")]
     SslHandshake(::openssl::ssl::Error),
     DomainRequired,
+    WsHandshake(String),
+    Proxy(String),
+    TlsConfig(String),
     Other(Box<std::error::Error>),
 }
 
@@ -48,6 +51,9 @@ impl fmt::Display for Error {
             Openssl(ref err) => write!(f, "{}: {}", self.description(), err),
             #[cfg(ssl)]
             SslHandshake(ref err) => write!(f, "{}: {}", self.description(), err),
+            WsHandshake(ref msg) => write!(f, "{}: {}", self.description(), msg),
+            Proxy(ref msg) => write!(f, "{}: {}", self.description(), msg),
+            TlsConfig(ref msg) => write!(f, "{}: {}", self.description(), msg),
             Other(ref err) => write!(f, "{}: {}", self.description(), err),
             _ => self.description().fmt(f),
         }
@@ -66,6 +72,9 @@ impl std::error::Error for Error {
             DomainRequired => {
                 "A domain for a host is required in order to establish an SSL connection"
             }
+            WsHandshake(_) => "WebSocket handshake error",
+            Proxy(_) => "Proxy error",
+            TlsConfig(_) => "Invalid TLS configuration",
             Other(_) => "Other error",
         }
     }
@@ -0,0 +1,299 @@
+use super::{NetworkStream, NetworkConnector, Result};
+use ::url::HostAndPort;
+use std::cmp;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{self, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Xorshift generator, so that a seed always gives the same faults.
+#[derive(Debug)]
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> XorShift {
+        // zero is a fixed point of xorshift
+        XorShift(if seed == 0 { 0x9e37_79b9_7f4a_7c15 } else { seed })
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && (self.next() >> 11) as f64 / (1u64 << 53) as f64 < probability
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Faults {
+    refuse_connect: f64,
+    drop_after: Option<usize>,
+    delay: Option<(f64, Duration)>,
+    would_block: f64,
+    timed_out: f64,
+    split_writes: Option<usize>,
+    corrupt: f64,
+}
+
+/// A connector injecting network faults into the streams of another one.
+///
+/// Faults are drawn from a generator seeded by `seed` and shared by every
+/// stream of the connector, so the same sequence of calls always fails the
+/// same way. Probabilities apply to each connect, read or write call.
+#[derive(Debug, Clone)]
+pub struct FaultConnector<C: NetworkConnector> {
+    base_connector: C,
+    faults: Faults,
+    rng: Arc<Mutex<XorShift>>,
+}
+
+impl<C: NetworkConnector> FaultConnector<C> {
+    pub fn new(base_connector: C, seed: u64) -> Self {
+        FaultConnector {
+            base_connector: base_connector,
+            faults: Faults::default(),
+            rng: Arc::new(Mutex::new(XorShift::new(seed))),
+        }
+    }
+
+    /// Fails connects with `ConnectionRefused`.
+    pub fn refuse_connects(mut self, probability: f64) -> Self {
+        self.faults.refuse_connect = probability;
+        self
+    }
+
+    /// Resets each connection once `bytes` were read and written over it.
+    pub fn drop_after(mut self, bytes: usize) -> Self {
+        self.faults.drop_after = Some(bytes);
+        self
+    }
+
+    /// Sleeps `delay` before reads and writes.
+    pub fn delay(mut self, probability: f64, delay: Duration) -> Self {
+        self.faults.delay = Some((probability, delay));
+        self
+    }
+
+    /// Fails reads and writes with `WouldBlock`.
+    pub fn would_block(mut self, probability: f64) -> Self {
+        self.faults.would_block = probability;
+        self
+    }
+
+    /// Fails reads and writes with `TimedOut`.
+    pub fn timed_out(mut self, probability: f64) -> Self {
+        self.faults.timed_out = probability;
+        self
+    }
+
+    /// Writes at most `max` bytes at a time, at least one.
+    pub fn split_writes(mut self, max: usize) -> Self {
+        self.faults.split_writes = Some(cmp::max(max, 1));
+        self
+    }
+
+    /// Flips the bits of one byte of the data read or written.
+    pub fn corrupt(mut self, probability: f64) -> Self {
+        self.faults.corrupt = probability;
+        self
+    }
+}
+
+impl<C> NetworkConnector for FaultConnector<C>
+    where C: NetworkConnector + 'static
+{
+    type Stream = FaultStream<C::Stream>;
+
+    fn connect(&self, host_port: &HostAndPort) -> Result<Self::Stream> {
+        if self.rng.lock().unwrap().chance(self.faults.refuse_connect) {
+            debug!("Fault: refusing connect to {}", host_port);
+            return Err(io::Error::new(ErrorKind::ConnectionRefused, "injected fault").into());
+        }
+        let stream = try!(self.base_connector.connect(host_port));
+        Ok(FaultStream {
+            inner: stream,
+            faults: self.faults.clone(),
+            rng: self.rng.clone(),
+            transferred: 0,
+        })
+    }
+}
+
+/// A stream of a `FaultConnector`.
+#[derive(Debug)]
+pub struct FaultStream<S> {
+    inner: S,
+    faults: Faults,
+    rng: Arc<Mutex<XorShift>>,
+    transferred: usize,
+}
+
+impl<S> FaultStream<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// How many more bytes may go over the connection, failing once it was
+    /// dropped.
+    fn budget(&self, wanted: usize) -> io::Result<usize> {
+        match self.faults.drop_after {
+            Some(limit) if self.transferred >= limit => {
+                Err(io::Error::new(ErrorKind::ConnectionReset, "injected fault"))
+            }
+            Some(limit) => Ok(cmp::min(wanted, limit - self.transferred)),
+            None => Ok(wanted),
+        }
+    }
+
+    /// Delays or fails a read or write call. The delay is slept without
+    /// holding the generator, so other streams are not held up.
+    fn before_io(&self) -> io::Result<()> {
+        let (delay, result) = {
+            let mut rng = self.rng.lock().unwrap();
+            let delay = match self.faults.delay {
+                Some((probability, delay)) if rng.chance(probability) => Some(delay),
+                _ => None,
+            };
+            let result = if rng.chance(self.faults.would_block) {
+                Err(io::Error::new(ErrorKind::WouldBlock, "injected fault"))
+            } else if rng.chance(self.faults.timed_out) {
+                Err(io::Error::new(ErrorKind::TimedOut, "injected fault"))
+            } else {
+                Ok(())
+            };
+            (delay, result)
+        };
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
+        result
+    }
+
+    fn maybe_corrupt(&self, data: &mut [u8]) {
+        let mut rng = self.rng.lock().unwrap();
+        if !data.is_empty() && rng.chance(self.faults.corrupt) {
+            let idx = rng.below(data.len());
+            data[idx] = !data[idx];
+        }
+    }
+}
+
+impl<S: Read> Read for FaultStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = try!(self.budget(buf.len()));
+        try!(self.before_io());
+        let n = try!(self.inner.read(&mut buf[..len]));
+        self.maybe_corrupt(&mut buf[..n]);
+        self.transferred += n;
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for FaultStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut len = try!(self.budget(buf.len()));
+        try!(self.before_io());
+        if let Some(max) = self.faults.split_writes {
+            let chunk = 1 + self.rng.lock().unwrap().below(max);
+            len = cmp::min(len, chunk);
+        }
+        let mut data = buf[..len].to_vec();
+        self.maybe_corrupt(&mut data);
+        let n = try!(self.inner.write(&data));
+        self.transferred += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: NetworkStream> NetworkStream for FaultStream<S> {
+    #[inline]
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    #[inline]
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(dur)
+    }
+
+    #[inline]
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(dur)
+    }
+
+    #[inline]
+    fn shutdown(&mut self, how: net::Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{ErrorKind, Read, Write};
+    use super::FaultConnector;
+    use netopt::{NetworkConnector, HostAndPort};
+    use netopt::mock::MockConnector;
+
+    fn host() -> HostAndPort {
+        HostAndPort::parse("127.0.0.1:1883").unwrap()
+    }
+
+    #[test]
+    fn fault_drop_after_test() {
+        let connector = FaultConnector::new(MockConnector::with_read_data(vec![1, 2, 3, 4]), 1)
+            .drop_after(6)
+            .split_writes(2);
+        let mut stream = connector.connect(&host()).unwrap();
+        let mut written = 0;
+        while written < 4 {
+            let n = stream.write(&[9; 4][written..]).unwrap();
+            assert!(n >= 1 && n <= 2);
+            written += n;
+        }
+        let mut buf = [0; 4];
+        assert_eq!(stream.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], &[1, 2]);
+        assert_eq!(stream.read(&mut buf).unwrap_err().kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn fault_seed_test() {
+        let outcomes = |seed| {
+            let connector = FaultConnector::new(MockConnector::new(), seed)
+                .refuse_connects(0.3)
+                .would_block(0.3)
+                .timed_out(0.3);
+            (0..32)
+                .map(|_| {
+                    match connector.connect(&host()) {
+                        Ok(mut stream) => stream.write(&[0]).map_err(|e| e.kind()),
+                        Err(_) => Err(ErrorKind::ConnectionRefused),
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+        let first = outcomes(42);
+        assert_eq!(first, outcomes(42));
+        assert!(first.contains(&Ok(1)));
+        assert!(first.contains(&Err(ErrorKind::ConnectionRefused)));
+        assert!(first.contains(&Err(ErrorKind::WouldBlock)));
+        assert!(first.contains(&Err(ErrorKind::TimedOut)));
+    }
+}
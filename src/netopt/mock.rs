@@ -1,8 +1,11 @@
 use super::*;
 use std::io::{self, Read, Write};
-use std::{cmp, mem};
+use std::{cmp, fmt, mem};
 use std::net::{self, ToSocketAddrs, SocketAddr};
 use std::time::Duration;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use mqtt3::{MqttRead, MqttWrite, Packet};
 
 #[derive(Clone, Debug)]
 pub struct MockStream {
@@ -125,10 +128,246 @@ impl NetworkConnector for MockConnector {
     }
 }
 
+/// A step of a `ScriptedConnector` conversation.
+#[derive(Clone)]
+enum Step {
+    Expect(Packet),
+    ExpectWith(&'static str, fn(&Packet) -> bool),
+    Send(Packet),
+    Disconnect,
+    RefuseConnect,
+}
+
+impl fmt::Debug for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Step::Expect(ref packet) => write!(f, "expect {:?}", packet),
+            Step::ExpectWith(what, _) => write!(f, "expect {}", what),
+            Step::Send(ref packet) => write!(f, "send {:?}", packet),
+            Step::Disconnect => write!(f, "disconnect"),
+            Step::RefuseConnect => write!(f, "refuse connect"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Script {
+    steps: VecDeque<Step>,
+    connects: usize,
+}
+
+/// A mock broker following a script: packets the client is expected to
+/// send, packets to answer with and dropped connections.
+///
+/// Streams panic as soon as the client writes a packet the script didn't
+/// expect. Reads fail with `WouldBlock` while the script waits for the
+/// client, like a read timing out, and return EOF once the connection was
+/// dropped. The script continues on the next connection.
+#[derive(Clone, Debug)]
+pub struct ScriptedConnector {
+    script: Arc<Mutex<Script>>,
+}
+
+impl ScriptedConnector {
+    pub fn new() -> Self {
+        ScriptedConnector {
+            script: Arc::new(Mutex::new(Script {
+                steps: VecDeque::new(),
+                connects: 0,
+            })),
+        }
+    }
+
+    fn push(self, step: Step) -> Self {
+        self.script.lock().unwrap().steps.push_back(step);
+        self
+    }
+
+    /// The client sends exactly `packet`.
+    pub fn expect(self, packet: Packet) -> Self {
+        self.push(Step::Expect(packet))
+    }
+
+    /// The client sends a packet accepted by `matches`, described by `what`
+    /// in failures.
+    pub fn expect_with(self, what: &'static str, matches: fn(&Packet) -> bool) -> Self {
+        self.push(Step::ExpectWith(what, matches))
+    }
+
+    /// The broker sends `packet`.
+    pub fn send(self, packet: Packet) -> Self {
+        self.push(Step::Send(packet))
+    }
+
+    /// The broker drops the connection.
+    pub fn disconnect(self) -> Self {
+        self.push(Step::Disconnect)
+    }
+
+    /// The next connection attempt is refused.
+    pub fn refuse_connect(self) -> Self {
+        self.push(Step::RefuseConnect)
+    }
+
+    /// Number of connections made so far.
+    pub fn connects(&self) -> usize {
+        self.script.lock().unwrap().connects
+    }
+
+    /// Panics unless every step of the script has been played.
+    pub fn assert_done(&self) {
+        let script = self.script.lock().unwrap();
+        if !script.steps.is_empty() {
+            panic!("mock broker script not finished, remaining steps: {:?}", script.steps);
+        }
+    }
+}
+
+impl NetworkConnector for ScriptedConnector {
+    type Stream = ScriptedStream;
+
+    fn connect(&self, host_port: &HostAndPort) -> Result<Self::Stream> {
+        let mut script = self.script.lock().unwrap();
+        if let Some(&Step::RefuseConnect) = script.steps.front() {
+            script.steps.pop_front();
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "refused by script").into());
+        }
+        script.connects += 1;
+        Ok(ScriptedStream {
+            script: self.script.clone(),
+            connection: script.connects,
+            peer_addr: try!(host_port.to_socket_addrs()).next().unwrap(),
+            read_data: VecDeque::new(),
+            write_data: Vec::new(),
+            closed: false,
+        })
+    }
+}
+
+pub struct ScriptedStream {
+    script: Arc<Mutex<Script>>,
+    connection: usize,
+    peer_addr: SocketAddr,
+    read_data: VecDeque<u8>,
+    write_data: Vec<u8>,
+    closed: bool,
+}
+
+impl ScriptedStream {
+    /// Plays the broker's steps up to the next packet expected from the
+    /// client.
+    fn play(&mut self, script: &mut Script) {
+        if script.connects != self.connection {
+            // a newer connection took over the script
+            self.closed = true;
+        }
+        while !self.closed {
+            match script.steps.front() {
+                Some(&Step::Send(_)) | Some(&Step::Disconnect) => (),
+                _ => return,
+            }
+            match script.steps.pop_front() {
+                Some(Step::Send(packet)) => {
+                    let mut data = Vec::new();
+                    data.write_packet(&packet).unwrap();
+                    self.read_data.extend(data);
+                }
+                _ => self.closed = true,
+            }
+        }
+    }
+
+    /// Checks the complete packets written so far against the script.
+    fn check_written(&mut self, script: &mut Script) {
+        while let Some(len) = packet_len(&self.write_data) {
+            let packet = io::Cursor::new(self.write_data.drain(..len).collect::<Vec<u8>>())
+                .read_packet()
+                .unwrap_or_else(|err| panic!("mock broker can't decode client packet: {:?}", err));
+            match script.steps.pop_front() {
+                Some(Step::Expect(ref expected)) if *expected == packet => (),
+                Some(Step::ExpectWith(_, matches)) if matches(&packet) => (),
+                Some(Step::Expect(expected)) => {
+                    panic!("mock broker expected {:?}, client sent {:?}", expected, packet)
+                }
+                Some(Step::ExpectWith(what, _)) => {
+                    panic!("mock broker expected {}, client sent {:?}", what, packet)
+                }
+                Some(step) => panic!("mock broker was about to {:?}, client sent {:?}", step, packet),
+                None => panic!("mock broker script finished, client sent {:?}", packet),
+            }
+            self.play(script);
+        }
+    }
+}
+
+impl Write for ScriptedStream {
+    fn write(&mut self, msg: &[u8]) -> io::Result<usize> {
+        let script = self.script.clone();
+        let mut script = script.lock().unwrap();
+        self.play(&mut script);
+        if self.closed {
+            return Err(io::Error::new(io::ErrorKind::ConnectionReset, "closed by script"));
+        }
+        self.write_data.extend_from_slice(msg);
+        self.check_written(&mut script);
+        Ok(msg.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for ScriptedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read_data.is_empty() {
+            let script = self.script.clone();
+            let mut script = script.lock().unwrap();
+            self.play(&mut script);
+        }
+        if self.read_data.is_empty() {
+            if self.closed {
+                return Ok(0);
+            }
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "script waits for the client"));
+        }
+        let len = cmp::min(buf.len(), self.read_data.len());
+        for (dst, src) in buf.iter_mut().zip(self.read_data.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl NetworkStream for ScriptedStream {
+    #[inline]
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+
+    #[inline]
+    fn set_read_timeout(&self, _dur: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn set_write_timeout(&self, _dur: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn shutdown(&mut self, _how: net::Shutdown) -> io::Result<()> {
+        self.closed = true;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::io::{Read, Write};
-    use super::{MockConnector, MockStream, NetworkStream, NetworkConnector};
+    use std::io::ErrorKind;
+    use mqtt3::{MqttRead, MqttWrite, Packet};
+    use super::{MockConnector, MockStream, ScriptedConnector, NetworkStream, NetworkConnector};
     use std::net::ToSocketAddrs;
     use url::{Host, HostAndPort};
 
@@ -179,4 +418,39 @@ mod test {
         mock.read_to_end(&mut vec).unwrap();
         assert_eq!(vec, vec![8, 9, 10]);
     }
+
+    #[test]
+    #[should_panic(expected = "mock broker expected")]
+    fn scripted_mismatch_test() {
+        let connector = ScriptedConnector::new()
+            .expect(Packet::Pingreq)
+            .send(Packet::Pingresp)
+            .expect(Packet::Pingreq);
+        let addr = HostAndPort { host: Host::parse("127.0.0.1").unwrap(), port: 1883 };
+        let mut client = connector.connect(&addr).unwrap();
+        client.write_packet(&Packet::Pingreq).unwrap();
+        assert_eq!(client.read_packet().unwrap(), Packet::Pingresp);
+        client.write_packet(&Packet::Disconnect).unwrap();
+    }
+
+    #[test]
+    fn scripted_disconnect_test() {
+        let connector = ScriptedConnector::new()
+            .send(Packet::Pingresp)
+            .disconnect()
+            .refuse_connect()
+            .expect(Packet::Pingreq);
+        let addr = HostAndPort { host: Host::parse("127.0.0.1").unwrap(), port: 1883 };
+        let mut client = connector.connect(&addr).unwrap();
+        assert_eq!(client.read_packet().unwrap(), Packet::Pingresp);
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+        assert!(client.write(&[0xc0]).is_err());
+
+        assert!(connector.connect(&addr).is_err());
+        let mut client = connector.connect(&addr).unwrap();
+        assert_eq!(client.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+        client.write_packet(&Packet::Pingreq).unwrap();
+        connector.assert_done();
+    }
 }
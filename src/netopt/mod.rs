@@ -2,8 +2,17 @@ extern crate url;
 
 #[cfg(feature = "ssl")]
 pub mod ssl;
+#[cfg(feature = "rustls")]
+pub mod rustls;
+pub mod tls;
 pub mod tcp;
+pub mod ws;
+pub mod proxy;
+#[cfg(unix)]
+pub mod unix;
 pub mod mock;
+pub mod fault;
+pub mod capture;
 pub mod error;
 
 use std::time::Duration;
@@ -148,7 +157,35 @@ impl NetworkConnector for BoxedConnector {
     }
 }
 
+/// Length of the first MQTT packet in `data` once it is complete. A
+/// malformed remaining length takes all of `data`, which then fails to
+/// decode.
+fn packet_len(data: &[u8]) -> Option<usize> {
+    let mut remaining = 0;
+    for i in 0..4 {
+        let byte = match data.get(1 + i) {
+            Some(byte) => *byte as usize,
+            None => return None,
+        };
+        remaining |= (byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            let len = 2 + i + remaining;
+            return if data.len() >= len { Some(len) } else { None };
+        }
+    }
+    Some(data.len())
+}
+
 pub use self::tcp::{TcpStream, TcpListener, TcpConnector};
+pub use self::ws::{WsStream, WsConnector};
+pub use self::proxy::{HttpProxyConnector, Socks5Connector, Proxy};
+pub use self::fault::{FaultConnector, FaultStream};
+pub use self::capture::{Capture, CaptureConnector, CaptureStream, ReplayConnector, ReplayStream};
+#[cfg(unix)]
+pub use self::unix::{UnixStream, UnixListener, UnixConnector};
 
 #[cfg(feature = "ssl")]
 pub use self::ssl::{SslConnector, SslStream, SslError};
+#[cfg(feature = "rustls")]
+pub use self::rustls::{RustlsConnector, RustlsStream};
+pub use self::tls::{TlsOptions, TlsVersion, ClientCert};
@@ -0,0 +1,234 @@
+use super::{NetworkStream, NetworkConnector, Result, Error};
+use super::tcp::TcpConnector;
+use super::tls::{TlsOptions, TlsVersion, ClientCert};
+use ::url::{Host, HostAndPort};
+use std::{fmt, result};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{self, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use rustls::{ClientConfig, ClientSession, ProtocolVersion, Session, StreamOwned};
+use rustls::internal::pemfile;
+use webpki::DNSNameRef;
+use webpki_roots;
+
+pub struct RustlsStream<S: Read + Write>(StreamOwned<ClientSession, S>);
+
+impl<S: Read + Write> RustlsStream<S> {
+    pub fn into_inner(self) -> S {
+        self.0.sock
+    }
+}
+
+impl<S: Read + Write> Read for RustlsStream<S> {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<S: Read + Write> Write for RustlsStream<S> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<S: Read + Write + fmt::Debug> fmt::Debug for RustlsStream<S> {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RustlsStream({:?})", self.0.sock)
+    }
+}
+
+impl<S: NetworkStream + 'static> NetworkStream for RustlsStream<S> {
+    #[inline]
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.0.sock.peer_addr()
+    }
+
+    #[inline]
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.sock.set_read_timeout(dur)
+    }
+
+    #[inline]
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.sock.set_write_timeout(dur)
+    }
+
+    fn shutdown(&mut self, how: net::Shutdown) -> io::Result<()> {
+        self.0.sess.send_close_notify();
+        let _ = self.0.flush();
+        self.0.sock.shutdown(how)
+    }
+}
+
+fn build_config(options: &TlsOptions) -> Result<ClientConfig> {
+    let mut config = ClientConfig::new();
+    config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    if let Some(path) = options.ca_file() {
+        try!(add_pem_file(&mut config, path));
+    }
+    if let Some(path) = options.ca_dir() {
+        for entry in try!(fs::read_dir(path)) {
+            let path = try!(entry).path();
+            if path.is_file() && add_pem_file(&mut config, &path).is_err() {
+                debug!("Skipping {}, not a PEM certificate", path.display());
+            }
+        }
+    }
+    match options.client_cert() {
+        Some(&ClientCert::Pem { ref cert, ref key, password: None }) => {
+            let certs = try!(read_pem(cert, pemfile::certs));
+            let mut keys = try!(read_pem(key, pemfile::pkcs8_private_keys));
+            if keys.is_empty() {
+                keys = try!(read_pem(key, pemfile::rsa_private_keys));
+            }
+            if certs.is_empty() || keys.is_empty() {
+                return Err(Error::TlsConfig("Client certificate or key missing".to_string()));
+            }
+            config.set_single_client_cert(certs, keys.remove(0));
+        }
+        Some(&ClientCert::Pem { .. }) => {
+            return Err(Error::TlsConfig("rustls doesn't support encrypted keys".to_string()));
+        }
+        Some(&ClientCert::Pkcs12 { .. }) => {
+            return Err(Error::TlsConfig("rustls doesn't support PKCS#12".to_string()));
+        }
+        None => (),
+    }
+    if let Some(version) = options.min_version() {
+        // rustls never speaks anything older than TLS 1.2
+        config.versions = match version {
+            TlsVersion::Tls10 | TlsVersion::Tls11 | TlsVersion::Tls12 => {
+                vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2]
+            }
+        };
+    }
+    if options.ciphers().is_some() {
+        return Err(Error::TlsConfig("rustls doesn't support cipher lists".to_string()));
+    }
+    Ok(config)
+}
+
+fn read_pem<T, F>(path: &Path, parse: F) -> Result<Vec<T>>
+    where F: Fn(&mut BufRead) -> result::Result<Vec<T>, ()>
+{
+    let mut reader = BufReader::new(try!(File::open(path)));
+    parse(&mut reader).map_err(|_| Error::TlsConfig(format!("Invalid PEM file {}", path.display())))
+}
+
+fn add_pem_file(config: &mut ClientConfig, path: &Path) -> Result<()> {
+    let mut reader = BufReader::new(try!(File::open(path)));
+    match config.root_store.add_pem_file(&mut reader) {
+        Ok((added, _)) if added > 0 => Ok(()),
+        _ => Err(Error::TlsConfig(format!("No CA certificate in {}", path.display()))),
+    }
+}
+
+/// TLS through rustls. Certificates are verified by webpki, which only
+/// knows about DNS names, so brokers addressed by IP need a hostname set in
+/// `TlsOptions`.
+#[derive(Clone)]
+pub struct RustlsConnector<C: NetworkConnector = TcpConnector> {
+    base_connector: C,
+    config: Arc<ClientConfig>,
+    hostname: Option<String>,
+}
+
+impl<C: NetworkConnector> RustlsConnector<C> {
+    pub fn new_with_config(base_connector: C, config: Arc<ClientConfig>) -> Self {
+        RustlsConnector {
+            base_connector: base_connector,
+            config: config,
+            hostname: None,
+        }
+    }
+
+    pub fn new(base_connector: C) -> Result<Self> {
+        Self::with_options(base_connector, &TlsOptions::new())
+    }
+
+    pub fn with_options(base_connector: C, options: &TlsOptions) -> Result<Self> {
+        let config = try!(build_config(options));
+        let mut connector = Self::new_with_config(base_connector, Arc::new(config));
+        connector.hostname = options.hostname().map(|h| h.to_string());
+        Ok(connector)
+    }
+}
+
+impl<C> NetworkConnector for RustlsConnector<C>
+    where C: NetworkConnector + 'static
+{
+    type Stream = RustlsStream<C::Stream>;
+
+    fn connect(&self, host_port: &HostAndPort) -> Result<Self::Stream> {
+        let name = match (&self.hostname, &host_port.host) {
+            (&Some(ref hostname), _) => hostname.clone(),
+            (&None, &Host::Domain(ref domain)) => domain.clone(),
+            (&None, _) => return Err(Error::DomainRequired),
+        };
+        let dns_name = try!(DNSNameRef::try_from_ascii_str(&name)
+            .map_err(|_| Error::TlsConfig(format!("Invalid DNS name {}", name))));
+        let stream = try!(self.base_connector.connect(host_port));
+        let session = ClientSession::new(&self.config, dns_name);
+        let mut stream = RustlsStream(StreamOwned::new(session, stream));
+        // complete the handshake now rather than on the first packet
+        while stream.0.sess.is_handshaking() {
+            try!(stream.0.sess.complete_io(&mut stream.0.sock));
+        }
+        Ok(stream)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use rustls::ProtocolVersion;
+    use super::RustlsConnector;
+    use netopt::{Error, HostAndPort, NetworkConnector, TlsOptions, TlsVersion, ClientCert};
+    use netopt::mock::MockConnector;
+
+    #[test]
+    fn rustls_options_test() {
+        let options = TlsOptions::new();
+        assert!(RustlsConnector::with_options(MockConnector::new(), &options).is_ok());
+
+        let mut options = TlsOptions::new();
+        options.set_min_version(TlsVersion::Tls12);
+        let connector = RustlsConnector::with_options(MockConnector::new(), &options).unwrap();
+        assert_eq!(connector.config.versions,
+                   vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2]);
+
+        let mut options = TlsOptions::new();
+        options.set_ciphers("HIGH:!aNULL".to_string());
+        assert!(RustlsConnector::with_options(MockConnector::new(), &options).is_err());
+
+        let mut options = TlsOptions::new();
+        options.set_client_cert(ClientCert::Pkcs12 {
+            path: "/nonexistent/client.p12".into(),
+            password: "secret".to_string(),
+        });
+        assert!(RustlsConnector::with_options(MockConnector::new(), &options).is_err());
+    }
+
+    #[test]
+    fn rustls_ip_host_test() {
+        let host = HostAndPort::parse("127.0.0.1:8883").unwrap();
+        let connector = RustlsConnector::with_options(MockConnector::new(), &TlsOptions::new())
+            .unwrap();
+        match connector.connect(&host) {
+            Err(Error::DomainRequired) => (),
+            Err(err) => panic!("expected DomainRequired, got {:?}", err),
+            Ok(_) => panic!("expected DomainRequired"),
+        }
+    }
+}
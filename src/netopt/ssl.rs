@@ -1,12 +1,19 @@
-use super::{NetworkStream, NetworkConnector, Result, Error};
+use super::{NetworkStream, NetworkConnector, Result};
 use super::tcp::TcpConnector;
+use super::tls::{TlsOptions, TlsVersion, ClientCert};
 use ::url::HostAndPort;
 use ::openssl::{ssl};
 use std::fmt;
+use std::fs::{self, File};
 use std::io::{self, Read, Write};
-use std::net::{self, SocketAddr};
+use std::net::{self, IpAddr, SocketAddr};
+use std::path::Path;
 use std::time::Duration;
-use openssl::ssl::{SslMethod, SslConnectorBuilder};
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::ssl::{SslMethod, SslConnectorBuilder, ConnectConfiguration, SslOption,
+                   SSL_OP_NO_SSLV2, SSL_OP_NO_SSLV3, SSL_OP_NO_TLSV1, SSL_OP_NO_TLSV1_1};
+use openssl::x509::X509;
 use url::Host;
 
 pub use openssl::ssl::Error as SslError;
@@ -71,10 +78,80 @@ impl<S: NetworkStream + 'static> NetworkStream for SslStream<S> {
     }
 }
 
+fn build_connector(options: &TlsOptions) -> Result<ssl::SslConnector> {
+    let mut builder = try!(SslConnectorBuilder::new(SslMethod::tls()));
+    {
+        let ctx = builder.builder_mut();
+        if let Some(path) = options.ca_file() {
+            try!(ctx.set_ca_file(path));
+        }
+        if let Some(path) = options.ca_dir() {
+            for entry in try!(fs::read_dir(path)) {
+                let path = try!(entry).path();
+                if !path.is_file() {
+                    continue;
+                }
+                match X509::stack_from_pem(&try!(read_file(&path))) {
+                    Ok(certs) => {
+                        for cert in certs {
+                            try!(ctx.cert_store_mut().add_cert(cert));
+                        }
+                    }
+                    Err(_) => debug!("Skipping {}, not a PEM certificate", path.display()),
+                }
+            }
+        }
+        match options.client_cert() {
+            Some(&ClientCert::Pem { ref cert, ref key, ref password }) => {
+                try!(ctx.set_certificate_chain_file(cert));
+                let pem = try!(read_file(key));
+                let key = match *password {
+                    Some(ref password) => {
+                        try!(PKey::private_key_from_pem_passphrase(&pem, password.as_bytes()))
+                    }
+                    None => try!(PKey::private_key_from_pem(&pem)),
+                };
+                try!(ctx.set_private_key(&key));
+                try!(ctx.check_private_key());
+            }
+            Some(&ClientCert::Pkcs12 { ref path, ref password }) => {
+                let pkcs12 = try!(Pkcs12::from_der(&try!(read_file(path))));
+                let parsed = try!(pkcs12.parse(password));
+                try!(ctx.set_certificate(&parsed.cert));
+                try!(ctx.set_private_key(&parsed.pkey));
+                for cert in parsed.chain {
+                    try!(ctx.add_extra_chain_cert(cert));
+                }
+                try!(ctx.check_private_key());
+            }
+            None => (),
+        }
+        if let Some(version) = options.min_version() {
+            let disabled = match version {
+                TlsVersion::Tls10 => SslOption::empty(),
+                TlsVersion::Tls11 => SSL_OP_NO_TLSV1,
+                TlsVersion::Tls12 => SSL_OP_NO_TLSV1 | SSL_OP_NO_TLSV1_1,
+            };
+            ctx.set_options(SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3 | disabled);
+        }
+        if let Some(ciphers) = options.ciphers() {
+            try!(ctx.set_cipher_list(ciphers));
+        }
+    }
+    Ok(builder.build())
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut data));
+    Ok(data)
+}
+
 #[derive(Clone)]
 pub struct SslConnector<C: NetworkConnector = TcpConnector> {
     base_connector: C,
     ssl_connector: ssl::SslConnector,
+    hostname: Option<String>,
 }
 
 impl<C: NetworkConnector> SslConnector<C> {
@@ -82,6 +159,7 @@ impl<C: NetworkConnector> SslConnector<C> {
         SslConnector {
             base_connector: base_connector,
             ssl_connector: ssl_connector,
+            hostname: None,
         }
     }
 
@@ -89,6 +167,38 @@ impl<C: NetworkConnector> SslConnector<C> {
         let connector = try!(SslConnectorBuilder::new(SslMethod::tls())).build();
         Ok(Self::new_with_ssl_connector(base_connector, connector))
     }
+
+    pub fn with_options(base_connector: C, options: &TlsOptions) -> Result<Self> {
+        let mut connector = Self::new_with_ssl_connector(base_connector,
+                                                         try!(build_connector(options)));
+        connector.hostname = options.hostname().map(|h| h.to_string());
+        Ok(connector)
+    }
+
+    /// The name or address the server certificate is verified against.
+    fn peer(&self, host: &Host) -> Peer {
+        match (&self.hostname, host) {
+            (&Some(ref hostname), _) => Peer::Name(hostname.clone()),
+            (&None, &Host::Domain(ref domain)) => Peer::Name(domain.clone()),
+            (&None, &Host::Ipv4(ip)) => Peer::Ip(IpAddr::V4(ip)),
+            (&None, &Host::Ipv6(ip)) => Peer::Ip(IpAddr::V6(ip)),
+        }
+    }
+
+    fn configure(&self, peer: &Peer) -> Result<ConnectConfiguration> {
+        let mut config = try!(self.ssl_connector.configure());
+        if let Peer::Ip(ip) = *peer {
+            // verified against the iPAddress entries of subjectAltName
+            try!(config.ssl_mut().param_mut().set_ip(ip));
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Peer {
+    Name(String),
+    Ip(IpAddr),
 }
 
 impl<C> NetworkConnector for SslConnector<C>
@@ -97,14 +207,66 @@ impl<C> NetworkConnector for SslConnector<C>
     type Stream = SslStream<C::Stream>;
 
     fn connect(&self, host_port: &HostAndPort) -> Result<Self::Stream> {
+        let peer = self.peer(&host_port.host);
+        let config = try!(self.configure(&peer));
         let stream = try!(self.base_connector.connect(host_port));
-        match host_port.host {
-            Host::Domain(ref domain) => {
-                // has the domain for certificate verification
-                let ssl_stream = try!(self.ssl_connector.connect(&domain, stream));
-                Ok(SslStream::from_ssl(ssl_stream))
+        let ssl_stream = match peer {
+            Peer::Name(ref name) => try!(config.connect(name, stream)),
+            // no SNI for IP addresses (RFC 6066), the address is verified instead
+            Peer::Ip(_) => {
+                try!(config
+                    .danger_connect_without_providing_domain_for_certificate_verification_and_server_name_indication(stream))
             }
-            Host::Ipv4(_) | Host::Ipv6(_) => Err(Error::DomainRequired),
+        };
+        Ok(SslStream::from_ssl(ssl_stream))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use super::{SslConnector, Peer};
+    use netopt::{TcpConnector, TlsOptions, TlsVersion, ClientCert};
+    use url::Host;
+
+    #[test]
+    fn tls_options_test() {
+        let mut options = TlsOptions::new();
+        options.set_min_version(TlsVersion::Tls12).set_ciphers("HIGH:!aNULL".to_string());
+        assert!(SslConnector::with_options(TcpConnector::new(), &options).is_ok());
+
+        options.set_ca_file("/nonexistent/ca.pem");
+        assert!(SslConnector::with_options(TcpConnector::new(), &options).is_err());
+
+        let mut options = TlsOptions::new();
+        options.set_client_cert(ClientCert::Pkcs12 {
+            path: "/nonexistent/client.p12".into(),
+            password: "secret".to_string(),
+        });
+        assert!(SslConnector::with_options(TcpConnector::new(), &options).is_err());
+    }
+
+    #[test]
+    fn tls_ip_host_test() {
+        let connector = SslConnector::new(TcpConnector::new()).unwrap();
+        let ipv4 = Ipv4Addr::new(127, 0, 0, 1);
+        let ipv6 = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1);
+        for &(ref host, ip) in [(Host::Ipv4(ipv4), IpAddr::V4(ipv4)),
+                                (Host::Ipv6(ipv6), IpAddr::V6(ipv6))]
+            .iter() {
+            let peer = connector.peer(host);
+            assert_eq!(peer, Peer::Ip(ip));
+            // sets the address on the verify parameters
+            assert!(connector.configure(&peer).is_ok());
         }
+
+        let peer = connector.peer(&Host::Domain("localhost".to_string()));
+        assert_eq!(peer, Peer::Name("localhost".to_string()));
+
+        let mut options = TlsOptions::new();
+        options.set_hostname("broker.example".to_string());
+        let connector = SslConnector::with_options(TcpConnector::new(), &options).unwrap();
+        assert_eq!(connector.peer(&Host::Ipv4(ipv4)),
+                   Peer::Name("broker.example".to_string()));
     }
 }
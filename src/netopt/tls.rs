@@ -0,0 +1,109 @@
+use std::path::{Path, PathBuf};
+
+/// Oldest protocol version a connection may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    Tls10,
+    Tls11,
+    Tls12,
+}
+
+/// Certificate and private key presented to the broker.
+#[derive(Debug, Clone)]
+pub enum ClientCert {
+    /// PEM certificate chain and private key, the key optionally encrypted
+    Pem {
+        cert: PathBuf,
+        key: PathBuf,
+        password: Option<String>,
+    },
+    /// PKCS#12 archive holding the certificate, its chain and the key
+    Pkcs12 {
+        path: PathBuf,
+        password: String,
+    },
+}
+
+/// TLS settings on top of the defaults of the TLS backend, which trust the
+/// system (OpenSSL) or Mozilla (rustls) roots.
+///
+/// The rustls backend doesn't support PKCS#12, encrypted keys or cipher
+/// lists, and fails to connect when they are set. It can't verify brokers
+/// addressed by IP either: connecting to one fails with
+/// `Error::DomainRequired` unless `set_hostname` is used.
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    ca_file: Option<PathBuf>,
+    ca_dir: Option<PathBuf>,
+    client_cert: Option<ClientCert>,
+    min_version: Option<TlsVersion>,
+    ciphers: Option<String>,
+    hostname: Option<String>,
+}
+
+impl TlsOptions {
+    pub fn new() -> TlsOptions {
+        TlsOptions::default()
+    }
+
+    /// Also trusts the PEM certificates in `path`.
+    pub fn set_ca_file<P: AsRef<Path>>(&mut self, path: P) -> &mut TlsOptions {
+        self.ca_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Also trusts the PEM certificates of every file in `path`.
+    pub fn set_ca_dir<P: AsRef<Path>>(&mut self, path: P) -> &mut TlsOptions {
+        self.ca_dir = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn set_client_cert(&mut self, client_cert: ClientCert) -> &mut TlsOptions {
+        self.client_cert = Some(client_cert);
+        self
+    }
+
+    pub fn set_min_version(&mut self, version: TlsVersion) -> &mut TlsOptions {
+        self.min_version = Some(version);
+        self
+    }
+
+    /// An OpenSSL cipher list string replacing the default one.
+    pub fn set_ciphers(&mut self, ciphers: String) -> &mut TlsOptions {
+        self.ciphers = Some(ciphers);
+        self
+    }
+
+    /// Sends `hostname` as SNI and verifies the certificate against it,
+    /// instead of the host being connected to. Brokers addressed by IP are
+    /// otherwise verified against the IP addresses of their certificate,
+    /// except with rustls which requires a hostname for them.
+    pub fn set_hostname(&mut self, hostname: String) -> &mut TlsOptions {
+        self.hostname = Some(hostname);
+        self
+    }
+
+    pub fn ca_file(&self) -> Option<&Path> {
+        self.ca_file.as_ref().map(|p| p.as_path())
+    }
+
+    pub fn ca_dir(&self) -> Option<&Path> {
+        self.ca_dir.as_ref().map(|p| p.as_path())
+    }
+
+    pub fn client_cert(&self) -> Option<&ClientCert> {
+        self.client_cert.as_ref()
+    }
+
+    pub fn min_version(&self) -> Option<TlsVersion> {
+        self.min_version
+    }
+
+    pub fn ciphers(&self) -> Option<&str> {
+        self.ciphers.as_ref().map(|c| &c[..])
+    }
+
+    pub fn hostname(&self) -> Option<&str> {
+        self.hostname.as_ref().map(|h| &h[..])
+    }
+}
@@ -0,0 +1,186 @@
+use super::*;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{self, SocketAddr, Ipv4Addr, SocketAddrV4};
+use std::os::unix::net as unix;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Unix sockets have no IP address, stands in for the peer address.
+fn unspecified_addr() -> SocketAddr {
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0))
+}
+
+pub struct UnixStream(unix::UnixStream);
+
+impl UnixStream {
+    pub fn connect<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let unix_stream = try!(unix::UnixStream::connect(path));
+        Ok(unix_stream.into())
+    }
+
+    pub fn into_inner(self) -> unix::UnixStream {
+        self.0
+    }
+}
+
+impl From<unix::UnixStream> for UnixStream {
+    fn from(unix_stream: unix::UnixStream) -> Self {
+        UnixStream(unix_stream)
+    }
+}
+
+impl Read for UnixStream {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for UnixStream {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl fmt::Debug for UnixStream {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Clone for UnixStream {
+    fn clone(&self) -> Self {
+        self.0.try_clone().unwrap().into()
+    }
+}
+
+impl NetworkStream for UnixStream {
+    #[inline]
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(unspecified_addr())
+    }
+
+    #[inline]
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(dur)
+    }
+
+    #[inline]
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_write_timeout(dur)
+    }
+
+    #[inline]
+    fn shutdown(&mut self, how: net::Shutdown) -> io::Result<()> {
+        match self.0.shutdown(how) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+            err => err,
+        }
+    }
+}
+
+pub struct UnixListener(unix::UnixListener);
+
+impl UnixListener {
+    pub fn into_inner(self) -> unix::UnixListener {
+        self.0
+    }
+
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let listener = try!(unix::UnixListener::bind(path));
+        Ok(listener.into())
+    }
+}
+
+impl From<unix::UnixListener> for UnixListener {
+    fn from(unix_listener: unix::UnixListener) -> Self {
+        UnixListener(unix_listener)
+    }
+}
+
+impl Clone for UnixListener {
+    fn clone(&self) -> Self {
+        self.0.try_clone().unwrap().into()
+    }
+}
+
+impl NetworkListener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept(&mut self) -> ::Result<(Self::Stream, SocketAddr)> {
+        let (stream, _) = try!(self.0.accept());
+        Ok((stream.into(), unspecified_addr()))
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(unspecified_addr())
+    }
+}
+
+/// A connector to the unix socket at `path`. The host and port it is asked
+/// to connect to are ignored, though stacked connectors such as
+/// `SslConnector` still verify against the host.
+#[derive(Debug, Clone)]
+pub struct UnixConnector {
+    path: PathBuf,
+}
+
+impl UnixConnector {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        UnixConnector { path: path.as_ref().to_path_buf() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl NetworkConnector for UnixConnector {
+    type Stream = UnixStream;
+
+    fn connect(&self, _host_port: &HostAndPort) -> Result<Self::Stream> {
+        Ok(try!(UnixStream::connect(&self.path)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::Shutdown;
+    use std::thread;
+    use rand::{self, Rng};
+    use super::{UnixListener, UnixConnector, NetworkStream, NetworkConnector, NetworkListener};
+    use url::{HostAndPort, Host};
+
+    #[test]
+    fn unix_server_client_test() {
+        let path = env::temp_dir().join(format!("mqttc_{}.sock", rand::thread_rng().gen::<u32>()));
+        let mut listener = UnixListener::bind(&path).unwrap();
+        let connector = UnixConnector::new(&path);
+
+        thread::spawn(move || {
+            let addr = HostAndPort { host: Host::Domain("localhost".to_string()), port: 0 };
+            let mut client = connector.connect(&addr).unwrap();
+            client.write(&[0, 1, 2, 3]).unwrap();
+            client.flush().unwrap();
+            client.shutdown(Shutdown::Both).unwrap();
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut req = Vec::new();
+        stream.read_to_end(&mut req).unwrap();
+        assert_eq!(req, vec![0, 1, 2, 3]);
+        fs::remove_file(&path).unwrap();
+    }
+}
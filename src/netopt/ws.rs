@@ -0,0 +1,435 @@
+use super::{NetworkStream, NetworkConnector, Result, Error};
+use super::tcp::TcpConnector;
+use base64;
+use sha1::Sha1;
+use ::url::HostAndPort;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{self, SocketAddr};
+use std::time::Duration;
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use rand::{self, Rng};
+
+const WS_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_RESPONSE_HEADER: usize = 8192;
+/// The largest MQTT packet, with a 4 byte remaining length.
+const DEFAULT_MAX_FRAME: usize = 268_435_460;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// MQTT over WebSocket binary frames.
+///
+/// Writes are buffered and sent as a single frame on `flush`, reads return
+/// the payload of data frames. Pings from the server are answered and a
+/// close frame reads as the end of the stream.
+pub struct WsStream<S> {
+    inner: S,
+    // raw bytes received but not parsed into a frame yet
+    raw: Vec<u8>,
+    payload: Vec<u8>,
+    payload_pos: usize,
+    write_buf: Vec<u8>,
+    closed: bool,
+    max_frame: usize,
+}
+
+impl<S: Read + Write> WsStream<S> {
+    fn new(inner: S, raw: Vec<u8>, max_frame: usize) -> Self {
+        WsStream {
+            inner: inner,
+            raw: raw,
+            payload: Vec::new(),
+            payload_pos: 0,
+            write_buf: Vec::new(),
+            closed: false,
+            max_frame: max_frame,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 14);
+        try!(frame.write_u8(0x80 | opcode));
+        if payload.len() < 126 {
+            try!(frame.write_u8(0x80 | payload.len() as u8));
+        } else if payload.len() <= 0xffff {
+            try!(frame.write_u8(0x80 | 126));
+            try!(frame.write_u16::<BigEndian>(payload.len() as u16));
+        } else {
+            try!(frame.write_u8(0x80 | 127));
+            try!(frame.write_u64::<BigEndian>(payload.len() as u64));
+        }
+        // clients must mask every frame
+        let mut mask = [0u8; 4];
+        BigEndian::write_u32(&mut mask, rand::thread_rng().gen::<u32>());
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        try!(self.inner.write_all(&frame));
+        self.inner.flush()
+    }
+
+    /// Parses the next complete frame out of `raw`, if any.
+    fn parse_frame(&mut self) -> io::Result<Option<(u8, Vec<u8>)>> {
+        if self.raw.len() < 2 {
+            return Ok(None);
+        }
+        let opcode = self.raw[0] & 0x0f;
+        let masked = self.raw[1] & 0x80 != 0;
+        let (len, mut offset) = match self.raw[1] & 0x7f {
+            126 if self.raw.len() >= 4 => (BigEndian::read_u16(&self.raw[2..]) as u64, 4),
+            127 if self.raw.len() >= 10 => (BigEndian::read_u64(&self.raw[2..]), 10),
+            126 | 127 => return Ok(None),
+            len => (len as u64, 2),
+        };
+        if len > self.max_frame as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("WebSocket frame of {} bytes too large", len)));
+        }
+        let len = len as usize;
+        let mut mask = None;
+        if masked {
+            if self.raw.len() < offset + 4 {
+                return Ok(None);
+            }
+            mask = Some([self.raw[offset], self.raw[offset + 1], self.raw[offset + 2], self.raw[offset + 3]]);
+            offset += 4;
+        }
+        let end = match offset.checked_add(len) {
+            Some(end) => end,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "WebSocket frame too large"))
+            }
+        };
+        if self.raw.len() < end {
+            return Ok(None);
+        }
+        let mut payload: Vec<u8> = self.raw.drain(..end).skip(offset).collect();
+        if let Some(mask) = mask {
+            for (i, b) in payload.iter_mut().enumerate() {
+                *b ^= mask[i % 4];
+            }
+        }
+        Ok(Some((opcode, payload)))
+    }
+
+    /// Reads frames until one carries data. Returns `false` once the server
+    /// closed the connection.
+    fn fill_payload(&mut self) -> io::Result<bool> {
+        loop {
+            if self.closed {
+                return Ok(false);
+            }
+            match try!(self.parse_frame()) {
+                Some((OP_BINARY, payload)) |
+                Some((OP_CONTINUATION, payload)) => {
+                    if !payload.is_empty() {
+                        self.payload = payload;
+                        self.payload_pos = 0;
+                        return Ok(true);
+                    }
+                }
+                Some((OP_TEXT, _)) => {
+                    // 1003: MQTT is only carried in binary frames
+                    self.closed = true;
+                    let _ = self.write_frame(OP_CLOSE, &[0x03, 0xeb]);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              "Unexpected WebSocket text frame"));
+                }
+                Some((OP_PING, payload)) => try!(self.write_frame(OP_PONG, &payload)),
+                Some((OP_PONG, _)) => (),
+                Some((OP_CLOSE, payload)) => {
+                    self.closed = true;
+                    let _ = self.write_frame(OP_CLOSE, &payload[..payload.len().min(2)]);
+                }
+                Some((opcode, _)) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("Unknown WebSocket opcode {}", opcode)));
+                }
+                None => {
+                    let mut buf = [0u8; 4096];
+                    let n = try!(self.inner.read(&mut buf));
+                    if n == 0 {
+                        return Ok(false);
+                    }
+                    self.raw.extend_from_slice(&buf[..n]);
+                }
+            }
+        }
+    }
+}
+
+impl<S: Read + Write> Read for WsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.payload_pos == self.payload.len() && !try!(self.fill_payload()) {
+            return Ok(0);
+        }
+        let n = buf.len().min(self.payload.len() - self.payload_pos);
+        buf[..n].copy_from_slice(&self.payload[self.payload_pos..self.payload_pos + n]);
+        self.payload_pos += n;
+        Ok(n)
+    }
+}
+
+impl<S: Read + Write> Write for WsStream<S> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.write_buf.is_empty() {
+            return self.inner.flush();
+        }
+        let payload = ::std::mem::replace(&mut self.write_buf, Vec::new());
+        self.write_frame(OP_BINARY, &payload)
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for WsStream<S> {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WsStream({:?})", self.inner)
+    }
+}
+
+impl<S: NetworkStream + 'static> NetworkStream for WsStream<S> {
+    #[inline]
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    #[inline]
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(dur)
+    }
+
+    #[inline]
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.inner.set_write_timeout(dur)
+    }
+
+    fn shutdown(&mut self, how: net::Shutdown) -> io::Result<()> {
+        if !self.closed {
+            // 1000, normal closure
+            let _ = self.write_frame(OP_CLOSE, &[0x03, 0xe8]);
+            self.closed = true;
+        }
+        self.inner.shutdown(how)
+    }
+}
+
+/// Performs the WebSocket upgrade with the `mqtt` subprotocol over the
+/// streams of `base_connector`. Stack it over an `SslConnector` for `wss://`.
+#[derive(Clone)]
+pub struct WsConnector<C: NetworkConnector = TcpConnector> {
+    base_connector: C,
+    path: String,
+    headers: Vec<(String, String)>,
+    max_frame: usize,
+}
+
+impl<C: NetworkConnector> WsConnector<C> {
+    pub fn new(base_connector: C) -> Self {
+        WsConnector {
+            base_connector: base_connector,
+            path: "/mqtt".to_string(),
+            headers: Vec::new(),
+            max_frame: DEFAULT_MAX_FRAME,
+        }
+    }
+
+    /// Fails reads with `InvalidData` on frames with a larger payload than
+    /// `bytes`. Defaults to the size of the largest MQTT packet.
+    pub fn with_max_frame(mut self, bytes: usize) -> Self {
+        self.max_frame = bytes;
+        self
+    }
+
+    /// The resource requested in the upgrade, `/mqtt` by default.
+    pub fn with_path<P: Into<String>>(mut self, path: P) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Adds a header to the upgrade request.
+    pub fn with_header<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    fn request(&self, host_port: &HostAndPort, key: &str) -> String {
+        let mut request = format!("GET {} HTTP/1.1\r\n\
+                                   Host: {}\r\n\
+                                   Upgrade: websocket\r\n\
+                                   Connection: Upgrade\r\n\
+                                   Sec-WebSocket-Key: {}\r\n\
+                                   Sec-WebSocket-Version: 13\r\n\
+                                   Sec-WebSocket-Protocol: mqtt\r\n",
+                                  self.path, host_port, key);
+        for &(ref name, ref value) in &self.headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        request
+    }
+}
+
+impl<C> NetworkConnector for WsConnector<C>
+    where C: NetworkConnector + 'static
+{
+    type Stream = WsStream<C::Stream>;
+
+    fn connect(&self, host_port: &HostAndPort) -> Result<Self::Stream> {
+        let mut stream = try!(self.base_connector.connect(host_port));
+        let mut nonce = [0u8; 16];
+        let mut rng = rand::thread_rng();
+        for b in nonce.iter_mut() {
+            *b = rng.gen::<u8>();
+        }
+        let key = base64::encode(&nonce);
+        try!(stream.write_all(self.request(host_port, &key).as_bytes()));
+        try!(stream.flush());
+
+        // read the response head, keeping whatever follows it
+        let mut head = Vec::new();
+        let end = loop {
+            if let Some(pos) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+            if head.len() > MAX_RESPONSE_HEADER {
+                return Err(Error::WsHandshake("Response header too large".to_string()));
+            }
+            let mut buf = [0u8; 1024];
+            let n = try!(stream.read(&mut buf));
+            if n == 0 {
+                return Err(Error::WsHandshake("Connection closed during upgrade".to_string()));
+            }
+            head.extend_from_slice(&buf[..n]);
+        };
+        let rest = head.split_off(end);
+        try!(check_response(&String::from_utf8_lossy(&head), &key));
+        Ok(WsStream::new(stream, rest, self.max_frame))
+    }
+}
+
+fn check_response(head: &str, key: &str) -> Result<()> {
+    let mut lines = head.split("\r\n");
+    let status = lines.next().unwrap_or("");
+    if status.split_whitespace().nth(1) != Some("101") {
+        return Err(Error::WsHandshake(format!("Upgrade refused: {}", status)));
+    }
+    let mut accepted = false;
+    let mut protocol = false;
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim().to_lowercase();
+        let value = parts.next().unwrap_or("").trim();
+        match &name[..] {
+            "sec-websocket-accept" => accepted = value == accept_key(key),
+            "sec-websocket-protocol" if value != "mqtt" => {
+                return Err(Error::WsHandshake(format!("Unexpected subprotocol {}", value)));
+            }
+            "sec-websocket-protocol" => protocol = true,
+            _ => (),
+        }
+    }
+    if !accepted {
+        Err(Error::WsHandshake("Invalid Sec-WebSocket-Accept".to_string()))
+    } else if !protocol {
+        Err(Error::WsHandshake("Subprotocol mqtt not selected".to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+fn accept_key(key: &str) -> String {
+    let mut data = key.as_bytes().to_vec();
+    data.extend_from_slice(WS_GUID.as_bytes());
+    base64::encode(&Sha1::from(&data).digest().bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{ErrorKind, Read, Write};
+    use super::{WsStream, DEFAULT_MAX_FRAME, accept_key, check_response};
+    use netopt::mock::MockStream;
+
+    #[test]
+    fn ws_accept_key_test() {
+        // example from RFC 6455
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        assert_eq!(accept_key(key), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        let head = "HTTP/1.1 101 Switching Protocols\r\n\
+                    Upgrade: websocket\r\n\
+                    Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+                    Sec-WebSocket-Protocol: mqtt\r\n\r\n";
+        assert!(check_response(head, key).is_ok());
+        assert!(check_response("HTTP/1.1 403 Forbidden\r\n\r\n", key).is_err());
+        let head = "HTTP/1.1 101 Switching Protocols\r\n\
+                    Upgrade: websocket\r\n\
+                    Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+        assert!(check_response(head, key).is_err());
+    }
+
+    #[test]
+    fn ws_frame_test() {
+        // a ping, then the payload split over a binary and a continuation frame
+        let read_data = vec![0x89, 0x00, 0x02, 0x02, 0x20, 0x02, 0x80, 0x02, 0x00, 0x00];
+        let mock = MockStream::with_read_data("localhost:1883", read_data);
+        let mut stream = WsStream::new(mock, Vec::new(), DEFAULT_MAX_FRAME);
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0x20, 0x02, 0x00, 0x00]);
+
+        stream.write_all(&[0xc0]).unwrap();
+        stream.write_all(&[0x00]).unwrap();
+        stream.flush().unwrap();
+        let written = stream.into_inner().drain_write_data();
+        // pong, then the masked binary frame
+        assert_eq!(&written[..2], &[0x8A, 0x80]);
+        let frame = &written[6..];
+        assert_eq!(&frame[..2], &[0x82, 0x82]);
+        let mask = &frame[2..6];
+        assert_eq!(frame[6] ^ mask[0], 0xc0);
+        assert_eq!(frame[7] ^ mask[1], 0x00);
+    }
+
+    #[test]
+    fn ws_text_frame_test() {
+        let read_data = vec![0x81, 0x02, b'h', b'i'];
+        let mock = MockStream::with_read_data("localhost:1883", read_data);
+        let mut stream = WsStream::new(mock, Vec::new(), DEFAULT_MAX_FRAME);
+        let mut buf = [0u8; 2];
+        assert_eq!(stream.read(&mut buf).unwrap_err().kind(), ErrorKind::InvalidData);
+        let written = stream.into_inner().drain_write_data();
+        // masked close frame with status 1003
+        assert_eq!(&written[..2], &[0x88, 0x82]);
+        let mask = &written[2..6];
+        assert_eq!([written[6] ^ mask[0], written[7] ^ mask[1]], [0x03, 0xeb]);
+    }
+
+    #[test]
+    fn ws_frame_too_large_test() {
+        // a binary frame claiming 2^64 - 1 bytes, then one over the limit
+        let mut read_data = vec![0x82, 0x7f];
+        read_data.extend_from_slice(&[0xff; 8]);
+        let mock = MockStream::with_read_data("localhost:1883", read_data);
+        let mut stream = WsStream::new(mock, Vec::new(), DEFAULT_MAX_FRAME);
+        let mut buf = [0u8; 4];
+        assert_eq!(stream.read(&mut buf).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let read_data = vec![0x82, 0x7e, 0x01, 0x00];
+        let mock = MockStream::with_read_data("localhost:1883", read_data);
+        let mut stream = WsStream::new(mock, Vec::new(), 255);
+        assert_eq!(stream.read(&mut buf).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,124 @@
+use mqtt3::Message;
+
+/// Whether `topic` matches the subscription `filter`: `+` matches exactly
+/// one level, `#` matches the parent level and any number of child levels.
+/// Topics starting with `$` are not matched by filters starting with a
+/// wildcard.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => (),
+            (Some(f), Some(t)) => {
+                if f != t {
+                    return false;
+                }
+            }
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+pub type Handler = Box<FnMut(&Message) + Send>;
+
+/// Identifies a handler registered with `Client::route`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RouteId(usize);
+
+/// Dispatches incomming messages to the handlers of every matching filter.
+pub struct Router {
+    last_id: usize,
+    routes: Vec<(RouteId, String, Handler)>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            last_id: 0,
+            routes: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, filter: String, handler: Handler) -> RouteId {
+        self.last_id += 1;
+        let id = RouteId(self.last_id);
+        self.routes.push((id, filter, handler));
+        id
+    }
+
+    pub fn remove(&mut self, id: RouteId) -> bool {
+        let len = self.routes.len();
+        self.routes.retain(|&(route_id, _, _)| route_id != id);
+        self.routes.len() != len
+    }
+
+    /// Calls every handler whose filter matches the message topic, returns
+    /// the number of handlers called.
+    pub fn dispatch(&mut self, message: &Message) -> usize {
+        let topic = message.topic.path();
+        let mut dispatched = 0;
+        for &mut (_, ref filter, ref mut handler) in self.routes.iter_mut() {
+            if topic_matches(filter, &topic) {
+                (*handler)(message);
+                dispatched += 1;
+            }
+        }
+        dispatched
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use mqtt3::{Message, QoS, ToTopicPath};
+    use super::{topic_matches, Router};
+
+    #[test]
+    fn topic_matches_test() {
+        assert!(topic_matches("sensors/+/temp", "sensors/1/temp"));
+        assert!(!topic_matches("sensors/+/temp", "sensors/1/2/temp"));
+        assert!(!topic_matches("sensors/+/temp", "sensors/temp"));
+        assert!(topic_matches("logs/#", "logs"));
+        assert!(topic_matches("logs/#", "logs/a/b"));
+        assert!(!topic_matches("logs/#", "log"));
+        assert!(topic_matches("+/+", "/a"));
+        assert!(topic_matches("a/+", "a/"));
+        assert!(topic_matches("#", "a/b/c"));
+        assert!(!topic_matches("#", "$SYS/broker"));
+        assert!(!topic_matches("+/broker", "$SYS/broker"));
+        assert!(topic_matches("$SYS/#", "$SYS/broker"));
+        assert!(!topic_matches("a/b", "a/b/c"));
+    }
+
+    #[test]
+    fn router_dispatch_test() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut router = Router::new();
+        let counter = calls.clone();
+        let id = router.add("sensors/+/temp".to_string(),
+                            Box::new(move |_: &Message| { counter.fetch_add(1, Ordering::SeqCst); }));
+        let counter = calls.clone();
+        router.add("sensors/#".to_string(),
+                   Box::new(move |_: &Message| { counter.fetch_add(10, Ordering::SeqCst); }));
+
+        let message = Message {
+            topic: "sensors/1/temp".to_topic_name().unwrap(),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            pid: None,
+            payload: Arc::new(vec![]),
+        };
+        assert_eq!(router.dispatch(&message), 2);
+        assert!(router.remove(id));
+        assert!(!router.remove(id));
+        assert_eq!(router.dispatch(&message), 1);
+        assert_eq!(calls.load(Ordering::SeqCst), 21);
+    }
+}
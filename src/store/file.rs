@@ -0,0 +1,414 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use mqtt3::{Message, MqttRead, MqttWrite, Packet, PacketIdentifier};
+use super::{Store, Entry, Error, Result, in_put_order};
+
+const PUT: u8 = 0x01;
+const DELETE: u8 = 0x02;
+const RELEASE: u8 = 0x03;
+
+/// Compaction is never attempted on logs with fewer records than this.
+const MIN_COMPACT_RECORDS: usize = 1024;
+
+/// When writes to the log are flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// `fsync` after every write
+    Always,
+    /// `fsync` after every `n` writes
+    Every(usize),
+    /// Leave it to the operating system
+    Never,
+}
+
+/// A `Store` backed by an append-only log file, so that QoS 2 flows survive
+/// a restart of the process.
+///
+/// Every `put`, `release` and `delete` appends a record to the log before
+/// the change is applied in memory. The live messages are also kept in
+/// memory, and the log is rewritten with only those once it mostly
+/// consists of stale records.
+pub struct FileStorage {
+    path: PathBuf,
+    file: File,
+    messages: BTreeMap<PacketIdentifier, Entry>,
+    sync: SyncPolicy,
+    unsynced: usize,
+    records: usize,
+    next_seq: u64,
+}
+
+impl FileStorage {
+    /// Opens or creates the log at `path`, syncing every write.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Box<FileStorage>> {
+        Self::open_with_sync(path, SyncPolicy::Always)
+    }
+
+    pub fn open_with_sync<P: AsRef<Path>>(path: P, sync: SyncPolicy) -> Result<Box<FileStorage>> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = try!(OpenOptions::new().read(true).append(true).create(true).open(&path));
+
+        let mut data = Vec::new();
+        try!(file.read_to_end(&mut data));
+        let mut messages = BTreeMap::new();
+        let (records, valid_len) = try!(replay(&data, &mut messages));
+        if valid_len < data.len() as u64 {
+            // the last write was interrupted, drop the partial record
+            warn!("Truncating incomplete record in {}", path.display());
+            try!(file.set_len(valid_len));
+        }
+
+        Ok(Box::new(FileStorage {
+            path: path,
+            file: file,
+            messages: messages,
+            sync: sync,
+            unsynced: 0,
+            records: records,
+            next_seq: records as u64,
+        }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Rewrites the log so that it only holds the live messages.
+    pub fn compact(&mut self) -> Result<()> {
+        let tmp_path = self.path.with_extension("compact");
+        {
+            let mut tmp = try!(File::create(&tmp_path));
+            let mut buf = Vec::new();
+            let mut records = 0;
+            // in put order, so that reopening keeps it
+            for message in in_put_order(&self.messages) {
+                let pid = message.pid.unwrap();
+                try!(encode_put(&mut buf, message));
+                records += 1;
+                if self.is_released(pid) {
+                    try!(encode_pid(&mut buf, RELEASE, pid));
+                    records += 1;
+                }
+            }
+            try!(tmp.write_all(&buf));
+            try!(tmp.sync_all());
+            self.records = records;
+        }
+        try!(fs::rename(&tmp_path, &self.path));
+        if self.sync != SyncPolicy::Never {
+            // the rename is only durable once the directory is synced
+            try!(sync_dir(&self.path));
+        }
+        self.file = try!(OpenOptions::new().read(true).append(true).open(&self.path));
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    /// Writes a record to the log, which must succeed before the change
+    /// is applied in memory.
+    fn append(&mut self, record: &[u8]) -> Result<()> {
+        try!(self.file.write_all(record));
+        self.records += 1;
+        self.unsynced += 1;
+        let sync = match self.sync {
+            SyncPolicy::Always => true,
+            SyncPolicy::Every(n) => self.unsynced >= n,
+            SyncPolicy::Never => false,
+        };
+        if sync {
+            try!(self.file.sync_data());
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    fn maybe_compact(&mut self) -> Result<()> {
+        if self.records >= MIN_COMPACT_RECORDS && self.records > 2 * self.messages.len() {
+            try!(self.compact());
+        }
+        Ok(())
+    }
+}
+
+impl Store for FileStorage {
+    fn put(&mut self, message: Message) -> Result<()> {
+        let mut record = Vec::new();
+        try!(encode_put(&mut record, &message));
+        try!(self.append(&record));
+        self.messages.insert(message.pid.unwrap(), Entry {
+            seq: self.next_seq,
+            released: false,
+            message: message,
+        });
+        self.next_seq += 1;
+        self.maybe_compact()
+    }
+
+    fn get(&mut self, pid: PacketIdentifier) -> Result<Message> {
+        match self.messages.get(&pid) {
+            Some(entry) => Ok(entry.message.clone()),
+            None => Err(Error::NotFound(pid))
+        }
+    }
+
+    fn delete(&mut self, pid: PacketIdentifier) -> Result<()> {
+        if !self.messages.contains_key(&pid) {
+            return Ok(());
+        }
+        let mut record = Vec::with_capacity(3);
+        try!(encode_pid(&mut record, DELETE, pid));
+        try!(self.append(&record));
+        self.messages.remove(&pid);
+        self.maybe_compact()
+    }
+
+    fn release(&mut self, pid: PacketIdentifier) -> Result<()> {
+        match self.messages.get(&pid) {
+            Some(entry) if entry.released => return Ok(()),
+            Some(_) => (),
+            None => return Err(Error::NotFound(pid)),
+        }
+        let mut record = Vec::with_capacity(3);
+        try!(encode_pid(&mut record, RELEASE, pid));
+        try!(self.append(&record));
+        if let Some(entry) = self.messages.get_mut(&pid) {
+            entry.released = true;
+        }
+        self.maybe_compact()
+    }
+
+    fn is_released(&self, pid: PacketIdentifier) -> bool {
+        self.messages.get(&pid).map_or(false, |entry| entry.released)
+    }
+
+    fn iter<'a>(&'a self) -> Box<Iterator<Item = &'a Message> + 'a> {
+        in_put_order(&self.messages)
+    }
+
+    fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        try!(self.file.set_len(0));
+        try!(self.file.sync_all());
+        self.messages.clear();
+        self.records = 0;
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+fn encode_put(buf: &mut Vec<u8>, message: &Message) -> Result<()> {
+    let mut packet = Vec::new();
+    let offset = buf.len() as u64;
+    try!(packet.write_packet(&Packet::Publish(message.to_pub(None, false)))
+        .map_err(|_| Error::Corrupted(offset)));
+    try!(buf.write_u8(PUT));
+    try!(buf.write_u32::<BigEndian>(packet.len() as u32));
+    buf.extend_from_slice(&packet);
+    Ok(())
+}
+
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    try!(try!(File::open(dir)).sync_all());
+    Ok(())
+}
+
+/// Directories can't be opened as files on other platforms.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn encode_pid(buf: &mut Vec<u8>, tag: u8, pid: PacketIdentifier) -> Result<()> {
+    try!(buf.write_u8(tag));
+    try!(buf.write_u16::<BigEndian>(pid.0));
+    Ok(())
+}
+
+/// Applies the records in `data` to `messages`, numbering the puts by
+/// their record. Returns the number of records and the length of the data
+/// up to the last complete record.
+fn replay(data: &[u8], messages: &mut BTreeMap<PacketIdentifier, Entry>) -> Result<(usize, u64)> {
+    let mut cursor = Cursor::new(data);
+    let mut records = 0;
+    loop {
+        let offset = cursor.position();
+        if offset == data.len() as u64 {
+            return Ok((records, offset));
+        }
+        let tag = try!(cursor.read_u8());
+        match tag {
+            PUT => {
+                let len = match cursor.read_u32::<BigEndian>() {
+                    Ok(len) => len as u64,
+                    Err(_) => return Ok((records, offset)),
+                };
+                let start = cursor.position();
+                if start + len > data.len() as u64 {
+                    return Ok((records, offset));
+                }
+                let mut packet = Cursor::new(&data[start as usize..(start + len) as usize]);
+                let message = match packet.read_packet() {
+                    Ok(Packet::Publish(publish)) => {
+                        try!(Message::from_pub(publish).map_err(|_| Error::Corrupted(offset)))
+                    }
+                    _ => return Err(Error::Corrupted(offset)),
+                };
+                match message.pid {
+                    Some(pid) => {
+                        messages.insert(pid, Entry {
+                            seq: records as u64,
+                            released: false,
+                            message: message,
+                        })
+                    }
+                    None => return Err(Error::Corrupted(offset)),
+                };
+                cursor.set_position(start + len);
+            }
+            DELETE => {
+                match cursor.read_u16::<BigEndian>() {
+                    Ok(pid) => messages.remove(&PacketIdentifier(pid)),
+                    Err(_) => return Ok((records, offset)),
+                };
+            }
+            RELEASE => {
+                match cursor.read_u16::<BigEndian>() {
+                    Ok(pid) => {
+                        if let Some(entry) = messages.get_mut(&PacketIdentifier(pid)) {
+                            entry.released = true;
+                        }
+                    }
+                    Err(_) => return Ok((records, offset)),
+                }
+            }
+            _ => return Err(Error::Corrupted(offset)),
+        }
+        records += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use rand::{self, Rng};
+    use mqtt3::{Message, PacketIdentifier, QoS, ToTopicPath};
+    use super::{FileStorage, SyncPolicy};
+    use store::Store;
+
+    fn temp_path() -> PathBuf {
+        let mut rng = rand::thread_rng();
+        env::temp_dir().join(format!("mqttc_store_{}.log", rng.gen::<u32>()))
+    }
+
+    fn message(pid: u16) -> Message {
+        Message {
+            topic: "a/b".to_topic_name().unwrap(),
+            qos: QoS::ExactlyOnce,
+            retain: false,
+            pid: Some(PacketIdentifier(pid)),
+            payload: Arc::new(vec![pid as u8; 3]),
+        }
+    }
+
+    #[test]
+    fn file_storage_reopen_test() {
+        let path = temp_path();
+        {
+            let mut store = FileStorage::open(&path).unwrap();
+            store.put(message(1)).unwrap();
+            store.put(message(2)).unwrap();
+            store.put(message(3)).unwrap();
+            store.delete(PacketIdentifier(2)).unwrap();
+        }
+        {
+            let mut store = FileStorage::open_with_sync(&path, SyncPolicy::Never).unwrap();
+            assert_eq!(store.get(PacketIdentifier(1)).unwrap(), message(1));
+            assert!(store.get(PacketIdentifier(2)).is_err());
+            assert_eq!(store.get(PacketIdentifier(3)).unwrap(), message(3));
+            store.compact().unwrap();
+        }
+        let mut store = FileStorage::open(&path).unwrap();
+        assert_eq!(store.records, 2);
+        assert_eq!(store.get(PacketIdentifier(3)).unwrap(), message(3));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_storage_release_test() {
+        let path = temp_path();
+        {
+            let mut store = FileStorage::open(&path).unwrap();
+            store.put(message(1)).unwrap();
+            store.put(message(2)).unwrap();
+            store.release(PacketIdentifier(2)).unwrap();
+            assert!(store.release(PacketIdentifier(3)).is_err());
+        }
+        {
+            let mut store = FileStorage::open(&path).unwrap();
+            assert!(!store.is_released(PacketIdentifier(1)));
+            assert!(store.is_released(PacketIdentifier(2)));
+            store.compact().unwrap();
+        }
+        let store = FileStorage::open(&path).unwrap();
+        assert_eq!(store.records, 3);
+        assert!(store.is_released(PacketIdentifier(2)));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_storage_put_order_test() {
+        let path = temp_path();
+        let pids = |store: &FileStorage| store.iter().map(|m| m.pid.unwrap().0).collect::<Vec<_>>();
+        {
+            let mut store = FileStorage::open(&path).unwrap();
+            store.put(message(65534)).unwrap();
+            store.put(message(65535)).unwrap();
+            store.put(message(1)).unwrap();
+            store.delete(PacketIdentifier(65534)).unwrap();
+            assert_eq!(pids(&store), vec![65535, 1]);
+        }
+        let mut store = FileStorage::open(&path).unwrap();
+        assert_eq!(pids(&store), vec![65535, 1]);
+        store.compact().unwrap();
+        store.put(message(2)).unwrap();
+        let store = FileStorage::open(&path).unwrap();
+        assert_eq!(pids(&store), vec![65535, 1, 2]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_storage_truncated_test() {
+        let path = temp_path();
+        {
+            let mut store = FileStorage::open(&path).unwrap();
+            store.put(message(1)).unwrap();
+            store.put(message(2)).unwrap();
+        }
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 2).unwrap();
+
+        let mut store = FileStorage::open(&path).unwrap();
+        assert_eq!(store.get(PacketIdentifier(1)).unwrap(), message(1));
+        assert!(store.get(PacketIdentifier(2)).is_err());
+        store.put(message(4)).unwrap();
+        drop(store);
+
+        let mut store = FileStorage::open(&path).unwrap();
+        assert_eq!(store.get(PacketIdentifier(4)).unwrap(), message(4));
+        fs::remove_file(&path).unwrap();
+    }
+}
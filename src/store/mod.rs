@@ -0,0 +1,154 @@
+use std::{error, fmt, io, result};
+use std::collections::BTreeMap;
+use mqtt3::{Message, PacketIdentifier};
+
+pub mod file;
+
+pub use self::file::{FileStorage, SyncPolicy};
+
+pub type Result<T> = result::Result<T, Error>;
+
+pub trait Store {
+    fn put(&mut self, message: Message) -> Result<()>;
+    fn get(&mut self, pid: PacketIdentifier) -> Result<Message>;
+    fn delete(&mut self, pid: PacketIdentifier) -> Result<()>;
+    /// Records that the QoS 2 flow of `pid` moved past the message: PUBREL
+    /// was sent for an outgoing one, or an incomming one was handed to the
+    /// user. A restarted client must not send or deliver it again.
+    fn release(&mut self, pid: PacketIdentifier) -> Result<()>;
+    fn is_released(&self, pid: PacketIdentifier) -> bool;
+    /// Stored messages, in the order they were put.
+    fn iter<'a>(&'a self) -> Box<Iterator<Item = &'a Message> + 'a>;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn clear(&mut self) -> Result<()>;
+}
+
+#[derive(Debug)]
+pub enum Error {
+    NotFound(PacketIdentifier),
+    Unavailable(PacketIdentifier),
+    Corrupted(u64),
+    Io(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NotFound(PacketIdentifier(packet_identifier)) =>
+                fmt::write(f, format_args!("Packet {} not found", packet_identifier)),
+            Error::Unavailable(PacketIdentifier(packet_identifier)) =>
+                fmt::write(f, format_args!("Packet {} unavailable", packet_identifier)),
+            Error::Corrupted(offset) =>
+                fmt::write(f, format_args!("Storage corrupted at offset {}", offset)),
+            Error::Io(ref err) => write!(f, "IO error: {}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::NotFound(PacketIdentifier(_)) =>  "Packet not found",
+            Error::Unavailable(PacketIdentifier(_)) => "Packet unavailable",
+            Error::Corrupted(_) => "Storage corrupted",
+            Error::Io(ref err) => err.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// A stored message, with when it was put and whether it was released.
+struct Entry {
+    seq: u64,
+    released: bool,
+    message: Message,
+}
+
+/// The messages of `entries` in put order. Packet identifiers wrap around,
+/// so their order is not the order the messages were published in.
+fn in_put_order<'a>(entries: &'a BTreeMap<PacketIdentifier, Entry>)
+                    -> Box<Iterator<Item = &'a Message> + 'a> {
+    let mut entries: Vec<&Entry> = entries.values().collect();
+    entries.sort_by_key(|entry| entry.seq);
+    Box::new(entries.into_iter().map(|entry| &entry.message))
+}
+
+pub struct MemoryStorage {
+    entries: BTreeMap<PacketIdentifier, Entry>,
+    next_seq: u64,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Box<MemoryStorage> {
+        Box::new(MemoryStorage {
+            entries: BTreeMap::new(),
+            next_seq: 0,
+        })
+    }
+}
+
+impl Store for MemoryStorage {
+    fn put(&mut self, message: Message) -> Result<()> {
+        self.entries.insert(message.pid.unwrap(), Entry {
+            seq: self.next_seq,
+            released: false,
+            message: message,
+        });
+        self.next_seq += 1;
+        Ok(())
+    }
+
+    fn get(&mut self, pid: PacketIdentifier) -> Result<Message> {
+        match self.entries.get(&pid) {
+            Some(entry) => Ok(entry.message.clone()),
+            None => Err(Error::NotFound(pid))
+        }
+    }
+
+    fn delete(&mut self, pid: PacketIdentifier) -> Result<()> {
+        self.entries.remove(&pid);
+        Ok(())
+    }
+
+    fn release(&mut self, pid: PacketIdentifier) -> Result<()> {
+        match self.entries.get_mut(&pid) {
+            Some(entry) => {
+                entry.released = true;
+                Ok(())
+            }
+            None => Err(Error::NotFound(pid)),
+        }
+    }
+
+    fn is_released(&self, pid: PacketIdentifier) -> bool {
+        self.entries.get(&pid).map_or(false, |entry| entry.released)
+    }
+
+    fn iter<'a>(&'a self) -> Box<Iterator<Item = &'a Message> + 'a> {
+        in_put_order(&self.entries)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.entries.clear();
+        Ok(())
+    }
+}
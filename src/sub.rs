@@ -17,6 +17,29 @@ impl Subscription {
     }
 }
 
+/// What the broker answered for one topic of a SUBSCRIBE.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubscribeResult {
+    pub topic: String,
+    pub requested: QoS,
+    /// `None` if the broker refused the subscription
+    pub granted: Option<QoS>,
+}
+
+impl SubscribeResult {
+    pub fn is_granted(&self) -> bool {
+        self.granted.is_some()
+    }
+
+    /// Whether the broker granted a lower QoS than requested.
+    pub fn is_downgraded(&self) -> bool {
+        match self.granted {
+            Some(qos) => qos.to_u8() < self.requested.to_u8(),
+            None => false,
+        }
+    }
+}
+
 pub trait ToSubTopics {
     type Iter: Iterator<Item=SubscribeTopic>;
     fn to_subscribe_topics(&self) -> Result<Self::Iter>;
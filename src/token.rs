@@ -0,0 +1,117 @@
+use std::sync::{Arc, Mutex};
+use mqtt3::PacketIdentifier;
+
+#[derive(Debug)]
+enum State<T> {
+    Pending,
+    Completed(T),
+    Failed,
+    Abandoned,
+}
+
+/// Handle on an acknowledged flow, pass it to `Client::wait` to block
+/// until the broker has answered.
+#[derive(Debug, Clone)]
+pub struct Token<T> {
+    pid: Option<PacketIdentifier>,
+    state: Arc<Mutex<State<T>>>,
+}
+
+/// The client side of a `Token`. Dropping it without calling `complete`
+/// or `fail` abandons the token.
+#[derive(Debug)]
+pub struct Completion<T>(Arc<Mutex<State<T>>>);
+
+pub fn token<T>(pid: Option<PacketIdentifier>) -> (Token<T>, Completion<T>) {
+    let state = Arc::new(Mutex::new(State::Pending));
+    (Token { pid: pid, state: state.clone() }, Completion(state))
+}
+
+impl<T> Completion<T> {
+    pub fn complete(self, value: T) {
+        *self.0.lock().unwrap() = State::Completed(value);
+    }
+
+    /// Ends the flow without an outcome, e.g. because the broker answered
+    /// with something the request can't be matched with.
+    pub fn fail(self) {
+        *self.0.lock().unwrap() = State::Failed;
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        let mut state = self.0.lock().unwrap();
+        if let State::Pending = *state {
+            *state = State::Abandoned;
+        }
+    }
+}
+
+impl<T: Clone> Token<T> {
+    /// Packet identifier of the flow, `None` for QoS 0 publishes.
+    pub fn pid(&self) -> Option<PacketIdentifier> {
+        self.pid
+    }
+
+    /// The outcome of the flow, once the broker has answered.
+    pub fn result(&self) -> Option<T> {
+        match *self.state.lock().unwrap() {
+            State::Completed(ref value) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    /// Whether the broker answered with something the flow could not be
+    /// completed with.
+    pub fn is_failed(&self) -> bool {
+        match *self.state.lock().unwrap() {
+            State::Failed => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the client gave up on the flow, e.g. because the connection
+    /// was lost before the broker answered.
+    pub fn is_abandoned(&self) -> bool {
+        match *self.state.lock().unwrap() {
+            State::Abandoned => true,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mqtt3::PacketIdentifier;
+    use super::token;
+
+    #[test]
+    fn token_test() {
+        let (token1, completion) = token(Some(PacketIdentifier(1)));
+        assert_eq!(token1.result(), None);
+        completion.complete(5);
+        assert_eq!(token1.result(), Some(5));
+        assert!(!token1.is_abandoned());
+
+        let (token2, completion) = token::<u8>(None);
+        drop(completion);
+        assert!(token2.is_abandoned());
+
+        let (token3, completion) = token::<u8>(None);
+        completion.fail();
+        assert!(token3.is_failed());
+        assert!(!token3.is_abandoned());
+    }
+
+    #[test]
+    fn token_clone_test() {
+        let (token, completion) = token::<u8>(Some(PacketIdentifier(1)));
+        let clone = token.clone();
+        assert!(!token.is_abandoned());
+        assert!(!clone.is_abandoned());
+        drop(completion);
+        assert!(token.is_abandoned());
+        assert!(clone.is_abandoned());
+    }
+}